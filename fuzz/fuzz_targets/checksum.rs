@@ -0,0 +1,47 @@
+// The internet checksum against a plain reimplementation, and the guarantee that it catches every
+// single bit error
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/iputils/icmp.rs"]
+mod icmp;
+
+use icmp::{Echo, IcmpPacket, Version};
+use std::net::Ipv6Addr;
+
+// Folds the carry back in after every word
+fn reference(bytes: &[u8], initial: u32) -> u32 {
+    let mut sum = initial;
+    for word in bytes.chunks(2) {
+        sum += u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0));
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum
+}
+
+fuzz_target!(|data: &[u8]| {
+    assert_eq!(icmp::sum(data, 0), reference(data, 0));
+    if data.len() < 36 {
+        return;
+    }
+    let (addrs, rest) = data.split_at(32);
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).unwrap());
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[16..]).unwrap());
+    let request = IcmpPacket::EchoRequest(Echo {
+        ident: u16::from_be_bytes([rest[0], rest[1]]),
+        seq: u16::from_be_bytes([rest[2], rest[3]]),
+        payload: &rest[4..],
+    });
+    for (version, pseudo) in [
+        (Version::V4, 0),
+        (Version::V6, icmp::pseudo_sum(&src, &dst, rest.len() + 4)),
+    ] {
+        let mut bytes = request.serialize(version, pseudo);
+        assert!(icmp::verify(&bytes, pseudo).is_ok());
+        let bit = rest[0] as usize % (bytes.len() * 8);
+        bytes[bit / 8] ^= 1 << (bit % 8);
+        assert!(icmp::verify(&bytes, pseudo).is_err());
+    }
+});
@@ -0,0 +1,28 @@
+// ICMP errors and the request they quote, which routers fill with whatever they like
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/iputils/icmp.rs"]
+mod icmp;
+
+use icmp::{IcmpPacket, Version};
+
+fuzz_target!(|data: &[u8]| {
+    for version in [Version::V4, Version::V6] {
+        let quote = match IcmpPacket::parse(data, version) {
+            Ok(IcmpPacket::DestinationUnreachable(quote))
+            | Ok(IcmpPacket::TimeExceeded(quote))
+            | Ok(IcmpPacket::SourceQuench(quote))
+            | Ok(IcmpPacket::PacketTooBig { quote, .. })
+            | Ok(IcmpPacket::ParameterProblem { quote, .. }) => quote,
+            _ => continue,
+        };
+        if let Some(echo) = quote.echo(version) {
+            // The quoted request ends where the quote does
+            assert!(quote.datagram.ends_with(echo.payload));
+            assert!(quote.datagram.len() >= echo.payload.len() + icmp::HEADER_LEN + 20);
+        }
+    }
+});
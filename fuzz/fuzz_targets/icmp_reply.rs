@@ -0,0 +1,41 @@
+// Whatever the receiver reads off the socket: an ICMP message, behind an IPv4 header on raw
+// ICMPv4 sockets. The first byte picks the address family and socket type.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/iputils/icmp.rs"]
+mod icmp;
+#[allow(dead_code)]
+#[path = "../../src/iputils/ipv4.rs"]
+mod ipv4;
+
+use icmp::{IcmpPacket, Version};
+use ipv4::Ipv4Header;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&mode, data)) = data.split_first() else {
+        return;
+    };
+    let version = match mode & 1 {
+        0 => Version::V4,
+        _ => Version::V6,
+    };
+    let icmp = if mode & 2 != 0 && version == Version::V4 {
+        match Ipv4Header::parse(data) {
+            Ok(h) => h.payload(data),
+            Err(_) => return,
+        }
+    } else {
+        data
+    };
+    let _ = icmp::verify(icmp, 0);
+    let Ok(packet) = IcmpPacket::parse(icmp, version) else {
+        return;
+    };
+    // Anything that parses has to survive a trip over the wire unchanged
+    let bytes = packet.serialize(version, 0);
+    assert!(icmp::verify(&bytes, 0).is_ok());
+    assert_eq!(IcmpPacket::parse(&bytes, version), Ok(packet));
+});
@@ -3,6 +3,9 @@ use std::fmt::Display;
 use std::io;
 use std::net;
 
+use crate::IP;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum RingError {
     ArgError,
@@ -10,6 +13,24 @@ pub enum RingError {
     DestParseError(net::AddrParseError),
     NetworkError,
     ChannelSendError,
+    NoSuchInterface(String),
+    // The destination has no usable address, `reason` comes from the resolver
+    ResolveError { host: String, reason: String },
+    // The kernel refused to give us an ICMP socket. `raw` tells whether only a raw socket was
+    // asked for (`-r`), which changes the advice we can give.
+    SocketError { ip: IP, raw: bool, err: io::Error },
+}
+
+impl RingError {
+    // Sockets of this address family can't exist on this host (e.g. IPv6 is disabled)
+    pub fn is_family_unsupported(&self) -> bool {
+        match self {
+            RingError::SocketError { err, .. } | RingError::IoError(err) => {
+                err.raw_os_error() == Some(libc::EAFNOSUPPORT)
+            }
+            _ => false,
+        }
+    }
 }
 
 // impl From
@@ -44,6 +65,43 @@ impl Display for RingError {
             RingError::ChannelSendError => {
                 write!(f, "\x1b[1;31mFailed to transport data\x1b[0m")
             }
+            RingError::NoSuchInterface(name) => {
+                write!(f, "\x1b[1;31mNo such interface: {name}\x1b[0m")
+            }
+            RingError::ResolveError { host, reason } => {
+                write!(f, "\x1b[1;31mCan't resolve {host}: {reason}\x1b[0m")
+            }
+            RingError::SocketError { ip, raw, err } => {
+                let family = if *ip == IP::V4 { "IPv4" } else { "IPv6" };
+                writeln!(
+                    f,
+                    "\x1b[1;31mCan't open an {family} ICMP socket: {err}\x1b[0m\n"
+                )?;
+                match err.raw_os_error() {
+                    Some(libc::EAFNOSUPPORT) => {
+                        let other = if *ip == IP::V4 { "-6" } else { "-4" };
+                        write!(
+                            f,
+                            "{family} is disabled on this host. Ring a different address family with {other}."
+                        )
+                    }
+                    Some(libc::EACCES) | Some(libc::EPERM) if *raw => write!(
+                        f,
+                        "Raw sockets need the CAP_NET_RAW capability. Either run ring as root, or grant it once with\n\
+                         \x20   sudo setcap cap_net_raw+ep $(command -v ring)\n\
+                         or drop -r to use an unprivileged ICMP socket."
+                    ),
+                    Some(libc::EACCES) | Some(libc::EPERM) => write!(
+                        f,
+                        "Unprivileged ICMP sockets are only allowed for groups inside net.ipv4.ping_group_range.\n\
+                         Allow every group with\n\
+                         \x20   sudo sysctl -w net.ipv4.ping_group_range=\"0 2147483647\"\n\
+                         or give ring the CAP_NET_RAW capability so it can use a raw socket instead\n\
+                         \x20   sudo setcap cap_net_raw+ep $(command -v ring)"
+                    ),
+                    _ => Ok(()),
+                }
+            }
         }
     }
 }
@@ -0,0 +1,460 @@
+// ICMP messages, see rfc 792 for IPv4 and rfc 4443 for IPv6. Every message starts with the same
+// 8 bytes, the meaning of the last four depending on the type:
+//
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |     Type      |     Code      |          Checksum             |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |   Identifier / MTU / Pointer / unused                         |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |     Payload, or the start of the datagram an error is about ...
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//
+// Only depends on std, so the fuzz targets can build it on its own.
+
+use std::fmt;
+use std::net::Ipv6Addr;
+
+pub const HEADER_LEN: usize = 8;
+// An IPv6 header has no options, extension headers come after it
+const IPV6_HEADER_LEN: usize = 40;
+const NEXT_HEADER_ICMPV6: u32 = 58;
+
+// ICMPv4 and ICMPv6 use different type numbers for the same messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V4,
+    V6,
+}
+
+impl Version {
+    fn echo_request(self) -> u8 {
+        match self {
+            Version::V4 => 8,
+            Version::V6 => 128,
+        }
+    }
+    fn echo_reply(self) -> u8 {
+        match self {
+            Version::V4 => 0,
+            Version::V6 => 129,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Echo<'a> {
+    pub ident: u16,
+    pub seq: u16,
+    pub payload: &'a [u8],
+}
+
+// What an error message quotes: the IP header and (at least) the first 8 bytes of the datagram
+// that caused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quote<'a> {
+    pub code: u8,
+    pub datagram: &'a [u8],
+}
+
+impl<'a> Quote<'a> {
+    // The echo request this error is about, if it is about one and quotes enough of it
+    pub fn echo(&self, version: Version) -> Option<Echo<'a>> {
+        let ip_len = match version {
+            Version::V4 => (self.datagram.first()? & 0x0f) as usize * 4,
+            Version::V6 => IPV6_HEADER_LEN,
+        };
+        // An IHL below 5 isn't a valid header
+        if ip_len < 20 {
+            return None;
+        }
+        let icmp = self.datagram.get(ip_len..)?;
+        if icmp.len() < HEADER_LEN || icmp[0] != version.echo_request() {
+            return None;
+        }
+        Some(Echo {
+            ident: u16::from_be_bytes([icmp[4], icmp[5]]),
+            seq: u16::from_be_bytes([icmp[6], icmp[7]]),
+            payload: &icmp[HEADER_LEN..],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpPacket<'a> {
+    EchoRequest(Echo<'a>),
+    EchoReply(Echo<'a>),
+    DestinationUnreachable(Quote<'a>),
+    TimeExceeded(Quote<'a>),
+    // ICMPv4 only, deprecated by rfc 6633 but still out there
+    SourceQuench(Quote<'a>),
+    // ICMPv6 only. IPv4 routers say this with Destination Unreachable code 4 instead.
+    PacketTooBig {
+        mtu: u32,
+        quote: Quote<'a>,
+    },
+    // `pointer` is the offset of the offending byte. ICMPv4 only has 8 bits for it.
+    ParameterProblem {
+        pointer: u32,
+        quote: Quote<'a>,
+    },
+    // Everything else (redirects, neighbour discovery, ...), kept as it came
+    Other {
+        mtype: u8,
+        code: u8,
+        rest: [u8; 4],
+        body: &'a [u8],
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    // Not even the 8 byte header
+    TooShort(usize),
+    BadChecksum,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(
+                f,
+                "ICMP message of {} bytes is shorter than its {} byte header",
+                len, HEADER_LEN
+            ),
+            ParseError::BadChecksum => write!(f, "ICMP checksum doesn't match"),
+        }
+    }
+}
+
+impl<'a> IcmpPacket<'a> {
+    // Reads the message structure. The checksum isn't looked at, see `verify`.
+    pub fn parse(bytes: &'a [u8], version: Version) -> Result<Self, ParseError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(ParseError::TooShort(bytes.len()));
+        }
+        let (mtype, code) = (bytes[0], bytes[1]);
+        let rest = [bytes[4], bytes[5], bytes[6], bytes[7]];
+        let body = &bytes[HEADER_LEN..];
+        let echo = Echo {
+            ident: u16::from_be_bytes([rest[0], rest[1]]),
+            seq: u16::from_be_bytes([rest[2], rest[3]]),
+            payload: body,
+        };
+        let quote = Quote {
+            code,
+            datagram: body,
+        };
+        let packet = match (version, mtype) {
+            (v, t) if t == v.echo_request() => IcmpPacket::EchoRequest(echo),
+            (v, t) if t == v.echo_reply() => IcmpPacket::EchoReply(echo),
+            (Version::V4, 3) | (Version::V6, 1) => IcmpPacket::DestinationUnreachable(quote),
+            (Version::V4, 11) | (Version::V6, 3) => IcmpPacket::TimeExceeded(quote),
+            (Version::V4, 4) => IcmpPacket::SourceQuench(quote),
+            (Version::V6, 2) => IcmpPacket::PacketTooBig {
+                mtu: u32::from_be_bytes(rest),
+                quote,
+            },
+            (Version::V4, 12) => IcmpPacket::ParameterProblem {
+                pointer: rest[0] as u32,
+                quote,
+            },
+            (Version::V6, 4) => IcmpPacket::ParameterProblem {
+                pointer: u32::from_be_bytes(rest),
+                quote,
+            },
+            _ => IcmpPacket::Other {
+                mtype,
+                code,
+                rest,
+                body,
+            },
+        };
+        Ok(packet)
+    }
+
+    // The wire format, checksum included. `pseudo` is the sum of the ICMPv6 pseudo-header (see
+    // `pseudo_sum`), 0 for ICMPv4. The kernel redoes ICMPv6 checksums on send anyway.
+    pub fn serialize(&self, version: Version, pseudo: u32) -> Vec<u8> {
+        let (mtype, code, rest, body) = match *self {
+            IcmpPacket::EchoRequest(e) => (version.echo_request(), 0, e.words(), e.payload),
+            IcmpPacket::EchoReply(e) => (version.echo_reply(), 0, e.words(), e.payload),
+            IcmpPacket::DestinationUnreachable(q) => {
+                let mtype = match version {
+                    Version::V4 => 3,
+                    Version::V6 => 1,
+                };
+                (mtype, q.code, [0; 4], q.datagram)
+            }
+            IcmpPacket::TimeExceeded(q) => {
+                let mtype = match version {
+                    Version::V4 => 11,
+                    Version::V6 => 3,
+                };
+                (mtype, q.code, [0; 4], q.datagram)
+            }
+            IcmpPacket::SourceQuench(q) => (4, q.code, [0; 4], q.datagram),
+            IcmpPacket::PacketTooBig { mtu, quote } => {
+                (2, quote.code, mtu.to_be_bytes(), quote.datagram)
+            }
+            IcmpPacket::ParameterProblem { pointer, quote } => match version {
+                Version::V4 => (12, quote.code, [pointer as u8, 0, 0, 0], quote.datagram),
+                Version::V6 => (4, quote.code, pointer.to_be_bytes(), quote.datagram),
+            },
+            IcmpPacket::Other {
+                mtype,
+                code,
+                rest,
+                body,
+            } => (mtype, code, rest, body),
+        };
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+        bytes.extend_from_slice(&[mtype, code, 0, 0]);
+        bytes.extend_from_slice(&rest);
+        bytes.extend_from_slice(body);
+        let checksum = !sum(&bytes, pseudo) as u16;
+        bytes[2..4].copy_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+}
+
+impl Echo<'_> {
+    fn words(&self) -> [u8; 4] {
+        let [a, b] = self.ident.to_be_bytes();
+        let [c, d] = self.seq.to_be_bytes();
+        [a, b, c, d]
+    }
+}
+
+// One's complement sum of `bytes` as 16 bit words (rfc 1071), starting from `initial` and folded
+// back to 16 bits. An odd last byte is padded with a zero.
+pub fn sum(bytes: &[u8], initial: u32) -> u32 {
+    let mut sum = initial as u64;
+    for word in bytes.chunks(2) {
+        let mut part = u64::from(word[0]) << 8;
+        if word.len() > 1 {
+            part += u64::from(word[1]);
+        }
+        sum += part;
+    }
+    while (sum >> 16) > 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u32
+}
+
+// ICMPv6 checksums also cover a pseudo-header (rfc 8200 section 8.1): the source and destination
+// addresses, the length of the ICMPv6 message and the next header (58). This is its folded sum.
+pub fn pseudo_sum(src: &Ipv6Addr, dst: &Ipv6Addr, len: usize) -> u32 {
+    let mut words = [0u8; 40];
+    words[..16].copy_from_slice(&src.octets());
+    words[16..32].copy_from_slice(&dst.octets());
+    words[32..36].copy_from_slice(&(len as u32).to_be_bytes());
+    words[36..].copy_from_slice(&NEXT_HEADER_ICMPV6.to_be_bytes());
+    sum(&words, 0)
+}
+
+// A message with a good checksum sums up to all ones, its checksum field included
+pub fn verify(bytes: &[u8], pseudo: u32) -> Result<(), ParseError> {
+    match sum(bytes, pseudo) {
+        0xffff => Ok(()),
+        _ => Err(ParseError::BadChecksum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Echo replies the kernel sent on 127.0.0.1 and ::1, checksums and all
+    const REPLY_V4: [u8; 13] = [
+        0x00, 0x00, 0xeb, 0xf3, 0x12, 0x34, 0x00, 0x07, b'r', b'i', b'n', b'g', b'!',
+    ];
+    const REPLY_V6: [u8; 13] = [
+        0x81, 0x00, 0x6a, 0xaa, 0x12, 0x34, 0x00, 0x07, b'r', b'i', b'n', b'g', b'!',
+    ];
+
+    fn loopback_v6(len: usize) -> u32 {
+        pseudo_sum(&Ipv6Addr::LOCALHOST, &Ipv6Addr::LOCALHOST, len)
+    }
+
+    fn echo(seq: u16) -> Echo<'static> {
+        Echo {
+            ident: 0x1234,
+            seq,
+            payload: b"ring!",
+        }
+    }
+
+    #[test]
+    fn parses_kernel_replies() {
+        let expected = IcmpPacket::EchoReply(echo(7));
+        assert_eq!(IcmpPacket::parse(&REPLY_V4, Version::V4), Ok(expected));
+        assert_eq!(IcmpPacket::parse(&REPLY_V6, Version::V6), Ok(expected));
+        assert_eq!(verify(&REPLY_V4, 0), Ok(()));
+        assert_eq!(verify(&REPLY_V6, loopback_v6(REPLY_V6.len())), Ok(()));
+    }
+
+    #[test]
+    fn serializes_like_the_kernel() {
+        let reply = IcmpPacket::EchoReply(echo(7));
+        assert_eq!(reply.serialize(Version::V4, 0), REPLY_V4);
+        assert_eq!(
+            reply.serialize(Version::V6, loopback_v6(REPLY_V6.len())),
+            REPLY_V6
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let datagram = [
+            0x45, 0, 0, 28, 0, 0, 0, 0, 1, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let quote = Quote {
+            code: 1,
+            datagram: &datagram,
+        };
+        let packets = [
+            (Version::V4, IcmpPacket::EchoRequest(echo(1))),
+            (Version::V6, IcmpPacket::EchoRequest(echo(u16::MAX))),
+            (Version::V4, IcmpPacket::DestinationUnreachable(quote)),
+            (Version::V6, IcmpPacket::DestinationUnreachable(quote)),
+            (Version::V4, IcmpPacket::TimeExceeded(quote)),
+            (Version::V6, IcmpPacket::TimeExceeded(quote)),
+            (Version::V4, IcmpPacket::SourceQuench(quote)),
+            (Version::V6, IcmpPacket::PacketTooBig { mtu: 1280, quote }),
+            (
+                Version::V4,
+                IcmpPacket::ParameterProblem { pointer: 12, quote },
+            ),
+            (
+                Version::V6,
+                IcmpPacket::ParameterProblem {
+                    pointer: 70000,
+                    quote,
+                },
+            ),
+            (
+                Version::V4,
+                IcmpPacket::Other {
+                    mtype: 5,
+                    code: 1,
+                    rest: [10, 0, 0, 254],
+                    body: &datagram,
+                },
+            ),
+        ];
+        for (version, packet) in packets {
+            let bytes = packet.serialize(version, 0);
+            assert_eq!(verify(&bytes, 0), Ok(()), "{packet:?}");
+            assert_eq!(IcmpPacket::parse(&bytes, version), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn odd_length_checksum() {
+        let packet = IcmpPacket::EchoRequest(Echo {
+            ident: 1,
+            seq: 2,
+            payload: b"odd",
+        });
+        let bytes = packet.serialize(Version::V4, 0);
+        assert_eq!(verify(&bytes, 0), Ok(()));
+    }
+
+    #[test]
+    fn catches_corruption() {
+        for at in 0..REPLY_V4.len() {
+            let mut reply = REPLY_V4;
+            reply[at] ^= 0x10;
+            assert_eq!(verify(&reply, 0), Err(ParseError::BadChecksum));
+        }
+    }
+
+    #[test]
+    fn icmpv6_checksum_covers_pseudo_header() {
+        // Without the pseudo-header, or for another destination, it doesn't add up
+        assert!(verify(&REPLY_V6, 0).is_err());
+        let other = "::2".parse().unwrap();
+        let pseudo = pseudo_sum(&Ipv6Addr::LOCALHOST, &other, REPLY_V6.len());
+        assert!(verify(&REPLY_V6, pseudo).is_err());
+    }
+
+    #[test]
+    fn too_short() {
+        for len in 0..HEADER_LEN {
+            assert_eq!(
+                IcmpPacket::parse(&REPLY_V4[..len], Version::V4),
+                Err(ParseError::TooShort(len))
+            );
+        }
+        // A bare header is fine, the payload is just empty
+        let bare = IcmpPacket::parse(&REPLY_V4[..HEADER_LEN], Version::V4);
+        assert!(matches!(bare, Ok(IcmpPacket::EchoReply(e)) if e.payload.is_empty()));
+    }
+
+    #[test]
+    fn finds_quoted_echo() {
+        let request = IcmpPacket::EchoRequest(echo(9)).serialize(Version::V4, 0);
+        // 24 byte header, so the IHL has to be honoured
+        let mut datagram = vec![0x46; 24];
+        datagram.extend_from_slice(&request);
+        let quote = Quote {
+            code: 0,
+            datagram: &datagram,
+        };
+        assert_eq!(quote.echo(Version::V4).map(|e| e.seq), Some(9));
+        // Only the first 8 bytes have to be quoted
+        let short = Quote {
+            code: 0,
+            datagram: &datagram[..32],
+        };
+        assert_eq!(short.echo(Version::V4).map(|e| e.ident), Some(0x1234));
+
+        let request = IcmpPacket::EchoRequest(echo(10)).serialize(Version::V6, 0);
+        let mut datagram = vec![0x60; 40];
+        datagram.extend_from_slice(&request);
+        let quote = Quote {
+            code: 0,
+            datagram: &datagram,
+        };
+        assert_eq!(quote.echo(Version::V6).map(|e| e.seq), Some(10));
+    }
+
+    #[test]
+    fn malformed_quotes() {
+        let request = IcmpPacket::EchoRequest(echo(9)).serialize(Version::V4, 0);
+        let mut datagram = vec![0x45; 20];
+        datagram.extend_from_slice(&request);
+        let quote = |datagram| Quote { code: 0, datagram };
+        // Cut short, empty, an IHL past the end or below 5, and a quote of something else
+        assert_eq!(quote(&datagram[..27]).echo(Version::V4), None);
+        assert_eq!(quote(&[]).echo(Version::V4), None);
+        assert_eq!(quote(&[0x4f; 30]).echo(Version::V4), None);
+        assert_eq!(quote(&[0x41; 30]).echo(Version::V4), None);
+        assert_eq!(quote(&datagram).echo(Version::V6), None);
+        let mut reply = datagram.clone();
+        reply[20] = 0;
+        assert_eq!(quote(&reply).echo(Version::V4), None);
+    }
+
+    #[test]
+    fn types_depend_on_version() {
+        // Type 3 is Destination Unreachable in ICMPv4 but Time Exceeded in ICMPv6
+        let bytes = [3, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            IcmpPacket::parse(&bytes, Version::V4),
+            Ok(IcmpPacket::DestinationUnreachable(_))
+        ));
+        assert!(matches!(
+            IcmpPacket::parse(&bytes, Version::V6),
+            Ok(IcmpPacket::TimeExceeded(_))
+        ));
+        // And an ICMPv4 echo reply is just some unknown type to ICMPv6
+        assert!(matches!(
+            IcmpPacket::parse(&REPLY_V4, Version::V6),
+            Ok(IcmpPacket::Other { mtype: 0, .. })
+        ));
+    }
+}
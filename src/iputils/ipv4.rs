@@ -0,0 +1,96 @@
+// The fixed part of an IPv4 header, see rfc 791. Raw ICMPv4 sockets hand us the whole datagram,
+// so the header has to be peeled off before the ICMP message can be read.
+//
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |Version|  IHL  |Type of Service|          Total Length         |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |         Identification        |Flags|      Fragment Offset    |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |  Time to Live |    Protocol   |         Header Checksum       |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                       Source Address                          |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                    Destination Address                        |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                    Options                    |    Padding    |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//
+// Only depends on std, like `icmp`, so the fuzz targets can build it on its own.
+
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ipv4Header<'a> {
+    // IHL converted to bytes
+    pub header_len: usize,
+    pub tos: u8,
+    pub total_len: usize,
+    pub ttl: u8,
+    pub options: &'a [u8],
+}
+
+const MIN_HEADER_LEN: usize = 20;
+const PROTOCOL_ICMP: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    // Not even the fixed 20 bytes
+    TooShort(usize),
+    // The version field isn't 4
+    NotIpv4(u8),
+    // IHL says the header is shorter than the fixed part or longer than what was read
+    BadHeaderLength(usize),
+    // Carries something other than ICMP
+    NotIcmp(u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TooShort(len) => write!(
+                f,
+                "IPv4 datagram of {} bytes is shorter than its {} byte header",
+                len, MIN_HEADER_LEN
+            ),
+            ParseError::NotIpv4(version) => write!(f, "IP version {} isn't IPv4", version),
+            ParseError::BadHeaderLength(len) => write!(f, "IPv4 header length of {} bytes", len),
+            ParseError::NotIcmp(protocol) => write!(f, "IPv4 protocol {} isn't ICMP", protocol),
+        }
+    }
+}
+
+impl<'a> Ipv4Header<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        if bytes.len() < MIN_HEADER_LEN {
+            return Err(ParseError::TooShort(bytes.len()));
+        }
+        if bytes[0] >> 4 != 4 {
+            return Err(ParseError::NotIpv4(bytes[0] >> 4));
+        }
+        // The low 4 bits of the first octet are the header length in 32-bit words
+        let header_len = ((bytes[0] & 0x0f) as usize) << 2;
+        if header_len < MIN_HEADER_LEN || header_len > bytes.len() {
+            return Err(ParseError::BadHeaderLength(header_len));
+        }
+        if bytes[9] != PROTOCOL_ICMP {
+            return Err(ParseError::NotIcmp(bytes[9]));
+        }
+        let total_len = ((bytes[2] as usize) << 8) | bytes[3] as usize;
+        Ok(Self {
+            header_len,
+            tos: bytes[1],
+            // Some stacks hand the datagram over with the length field already adjusted, so
+            // never trust it past what was actually read.
+            total_len: total_len.clamp(header_len, bytes.len()),
+            ttl: bytes[8],
+            options: &bytes[MIN_HEADER_LEN..header_len],
+        })
+    }
+
+    // The ICMP message carried by this datagram
+    pub fn payload<'b>(&self, bytes: &'b [u8]) -> &'b [u8] {
+        &bytes[self.header_len..self.total_len]
+    }
+}
@@ -1,135 +1,269 @@
-use std::net::SocketAddr;
-use std::ops::ControlFlow;
-use std::vec::IntoIter;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::RingError;
-use crate::DATA;
-
-pub fn get_ip4_addr(mut socket: IntoIter<SocketAddr>) -> Result<SocketAddr, RingError> {
-    let ipv4addr = socket.try_for_each(|addr| {
-        if addr.is_ipv4() {
-            if let std::net::IpAddr::V4(ip) = addr.ip() {
-                if ip.is_loopback() {
-                    println!("\n\x1b[1;33m[WARNING]: Ringing a loopback address\x1b[0m");
-                }
-            }
-            return ControlFlow::Break(addr);
-        }
-        ControlFlow::Continue(())
-    });
+use crate::IP;
 
-    if let ControlFlow::Break(a) = ipv4addr {
-        Ok(a)
-    } else {
-        Err(RingError::NetworkError)
+pub mod icmp;
+pub mod ipv4;
+pub mod rdns;
+pub mod recv;
+#[cfg(test)]
+pub mod sim;
+pub mod timefmt;
+pub mod tstamp;
+pub mod wire;
+
+const PING_GROUP_RANGE: &str = "/proc/sys/net/ipv4/ping_group_range";
+// Where `ip netns add` pins named network namespaces
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+// Unprivileged `SOCK_DGRAM` ICMP sockets are only handed out to groups inside
+// `net.ipv4.ping_group_range` (the same sysctl covers ICMPv6). The default "1 0" excludes everyone.
+pub fn ping_group_allowed() -> bool {
+    let range = match std::fs::read_to_string(PING_GROUP_RANGE) {
+        Ok(r) => r,
+        // Not linux, or /proc isn't mounted. Let the kernel decide.
+        Err(_) => return true,
+    };
+    let mut bounds = range.split_whitespace().map(|b| b.parse::<u32>());
+    let (low, high) = match (bounds.next(), bounds.next()) {
+        (Some(Ok(l)), Some(Ok(h))) => (l, h),
+        _ => return true,
+    };
+    let mut groups = vec![unsafe { libc::getegid() }];
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count > 0 {
+        let mut extra = vec![0 as libc::gid_t; count as usize];
+        let count = unsafe { libc::getgroups(count, extra.as_mut_ptr()) };
+        extra.truncate(count.max(0) as usize);
+        groups.extend(extra);
     }
+    groups.iter().any(|g| (low..=high).contains(g))
 }
 
-pub fn get_ip6_addr(socket: IntoIter<SocketAddr>) -> Result<SocketAddr, RingError> {
-    // Very hacky
-    let ipv6addr = socket.clone().try_for_each(|addr| {
-        if addr.is_ipv6() {
-            if let std::net::IpAddr::V6(ip) = addr.ip() {
-                if ip.is_loopback() {
-                    println!("\n\x1b[1;33m[WARNING]: Ringing a loopback address\x1b[0m");
-                }
-            }
-            return ControlFlow::Break(addr);
-        }
-        ControlFlow::Continue(())
-    });
+fn icmp_socket(ip: IP, raw: bool) -> io::Result<Socket> {
+    let ty = if raw { Type::RAW } else { Type::DGRAM };
+    match ip {
+        IP::V4 => Socket::new(Domain::IPV4, ty, Some(Protocol::ICMPV4)),
+        IP::V6 => Socket::new(Domain::IPV6, ty, Some(Protocol::ICMPV6)),
+    }
+}
+
+// TCP rings (--tcp) send nothing through their socket, but connecting a UDP one picks the route and
+// source address for the handshakes like an ICMP socket would, without needing ICMP permissions
+pub fn route_socket(ip: IP) -> Result<Socket, RingError> {
+    let domain = match ip {
+        IP::V4 => Domain::IPV4,
+        IP::V6 => Domain::IPV6,
+    };
+    Ok(Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?)
+}
 
-    if let ControlFlow::Break(a) = ipv6addr {
-        Ok(a)
+// Open an ICMP socket for `ip`, and report whether it ended up raw. When the kernel won't hand out
+// an unprivileged socket we try a raw one, so privileged users never have to know about `-r`.
+pub fn open_socket(ip: IP, raw: bool) -> Result<(Socket, bool), RingError> {
+    if raw {
+        return match icmp_socket(ip, true) {
+            Ok(s) => Ok((s, true)),
+            Err(err) => Err(RingError::SocketError { ip, raw, err }),
+        };
+    }
+    let err = if ping_group_allowed() {
+        match icmp_socket(ip, false) {
+            Ok(s) => return Ok((s, false)),
+            Err(e) if is_permission_error(&e) => e,
+            Err(err) => return Err(RingError::SocketError { ip, raw, err }),
+        }
     } else {
-        Err(RingError::NetworkError)
+        io::Error::from_raw_os_error(libc::EACCES)
+    };
+    match icmp_socket(ip, true) {
+        Ok(s) => {
+            println!("\n\x1b[1;33m[NOTE]: Unprivileged ICMP sockets aren't allowed for this user, using a raw socket\x1b[0m");
+            Ok((s, true))
+        }
+        // The raw socket failing too is expected for most users, so explain why the unprivileged
+        // one was refused
+        Err(e) if is_permission_error(&e) => Err(RingError::SocketError { ip, raw, err }),
+        // Anything else (IPv6 disabled, out of file descriptors) isn't about permissions and would
+        // have failed the unprivileged socket too. When ping_group_range made us skip that one, it
+        // is also the only real error, so it takes over (main falls back to IPv4 on EAFNOSUPPORT).
+        Err(raw_err) => Err(RingError::SocketError {
+            ip,
+            raw,
+            err: raw_err,
+        }),
     }
 }
-// fn psuedo_check(pheader: &[u8]) -> u32 {}
-
-#[derive(Debug, PartialEq, Eq)]
-pub struct EchoICMP<'a> {
-    pub echo_type: u8,
-    pub code: u8,
-    pub identifier: [u8; 2],
-    pub seq_num: u16,
-    pub base_chcksm: u32,
-    pub echo_data: &'a [u8; 21],
-}
-
-impl<'a> Default for EchoICMP<'a> {
-    fn default() -> Self {
-        Self {
-            echo_type: 128,
-            code: 0,
-            identifier: [0; 2],
-            seq_num: 0,
-            echo_data: DATA,
-            base_chcksm: 0,
-        }
+
+fn is_permission_error(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EACCES) | Some(libc::EPERM))
+}
+
+// The kernel picks the echo identifier for `SOCK_DGRAM` sockets and only hands us the replies that
+// carry it. Raw sockets see every ICMP message on the host, so we stamp our own (the pid, like
+// iputils) and filter on it.
+pub fn raw_identifier() -> u16 {
+    std::process::id() as u16
+}
+
+// Destinations that more than one host may answer: the IPv4 limited broadcast address and every
+// IPv4/IPv6 multicast group. Subnet-directed broadcasts can't be told apart from unicast without
+// knowing the netmask, so those still rely on `-b`.
+pub fn is_one_to_many(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_broadcast() || ip.is_multicast(),
+        IpAddr::V6(ip) => ip.is_multicast(),
     }
 }
 
-impl<'b> EchoICMP<'b> {
-    pub fn new() -> Self {
-        Self::default()
+// fe80::/10 and ff02::/16 (and the other link-local scoped groups) can't be reached without a
+// scope id telling the kernel which link to use
+pub fn is_link_local_v6(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V6(ip) if ip.is_multicast() => (ip.segments()[0] & 0x000f) == 2,
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+        IpAddr::V4(_) => false,
     }
-    pub fn new_v4() -> Self {
-        let mut temp = Self::default();
-        temp.echo_type = 8;
-        temp
+}
+
+// Map an interface name like `eth0` to its kernel index
+pub fn if_index(name: &str) -> Result<u32, RingError> {
+    let index = match CString::new(name) {
+        Ok(c) => unsafe { libc::if_nametoindex(c.as_ptr()) },
+        Err(_) => 0,
+    };
+    if index == 0 {
+        return Err(RingError::NoSuchInterface(name.to_owned()));
     }
-    // Change this function to accept a bool to indicate where it should return the checksum or not
-    // fn calc_checksum(&mut self, bytes: &mut [u8; 14], some: bool ) -> Option<[u8; 2]>
-    pub fn update_chksm(&mut self, bytes: &mut [u8]) {
-        bytes[2] = 0;
-        bytes[3] = 0;
-        let mut sum = self.base_chcksm;
-        // for word in bytes.chunks(2) {
-        let mut part = u16::from(bytes[6]) << 8;
-        part += u16::from(bytes[7]);
-        sum = sum.wrapping_add(u32::from(part));
-        // }
-
-        while (sum >> 16) > 0 {
-            sum = (sum & 0xffff) + (sum >> 16);
-        }
-        let sum = !sum as u16;
-        bytes[2] = (sum >> 8) as u8;
-        bytes[3] = (sum & 0xff) as u8;
+    Ok(index)
+}
+
+// DSCP code points from rfc 2474, 2597, 3246, 5865 and 8622, by name
+const DSCP_NAMES: [(&str, u8); 24] = [
+    ("be", 0),
+    ("default", 0),
+    ("le", 1),
+    ("cs1", 8),
+    ("af11", 10),
+    ("af12", 12),
+    ("af13", 14),
+    ("cs2", 16),
+    ("af21", 18),
+    ("af22", 20),
+    ("af23", 22),
+    ("cs3", 24),
+    ("af31", 26),
+    ("af32", 28),
+    ("af33", 30),
+    ("cs4", 32),
+    ("af41", 34),
+    ("af42", 36),
+    ("af43", 38),
+    ("cs5", 40),
+    ("va", 44),
+    ("ef", 46),
+    ("cs6", 48),
+    ("cs7", 56),
+];
+
+// Turn the argument of -Q into a TOS byte. Accepts a DSCP name (`ef`, `af41`, `cs6`, ...) or a
+// raw TOS/traffic class value in decimal or hex (`0xb8`), like iputils.
+pub fn parse_tos(value: &str) -> Option<u8> {
+    let lower = value.to_ascii_lowercase();
+    if let Some((_, dscp)) = DSCP_NAMES.iter().find(|(name, _)| *name == lower) {
+        return Some(dscp << 2);
     }
+    match lower.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => lower.parse().ok(),
+    }
+}
 
-    fn calc_checksum(&mut self, bytes: &[u8]) -> u32 {
-        let mut chck = 0u32;
-        for word in bytes.chunks(2) {
-            let mut part = u16::from(word[0]) << 8;
-            if word.len() > 1 {
-                part += u16::from(word[1]);
-            }
-            chck = chck.wrapping_add(u32::from(part));
-        }
-        while (chck >> 16) > 0 {
-            chck = (chck & 0xffff) + (chck >> 16);
-        }
-        chck
+// Move this thread into a network namespace, either one named by `ip netns add` or any namespace
+// file like `/proc/<pid>/ns/net`. Sockets opened afterwards live in that namespace, so the ring
+// uses its interfaces and routing tables. Needs CAP_SYS_ADMIN.
+pub fn enter_netns(name: &str) -> io::Result<()> {
+    let path = if name.contains('/') {
+        name.to_owned()
+    } else {
+        format!("{NETNS_RUN_DIR}/{name}")
+    };
+    let ns = std::fs::File::open(path)?;
+    if unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
+}
 
-    pub fn increase_seq(&mut self, container: &mut [u8]) {
-        self.seq_num += 1;
-        container[6] = (self.seq_num >> 8) as u8;
-        container[7] = (self.seq_num & 0x00FF) as u8;
+// VRF master devices announce themselves in their uevent. If sysfs can't tell us, trust the user.
+pub fn is_vrf(name: &str) -> bool {
+    match std::fs::read_to_string(format!("/sys/class/net/{name}/uevent")) {
+        Ok(uevent) => uevent.lines().any(|l| l == "DEVTYPE=vrf"),
+        Err(_) => true,
     }
+}
 
-    // initialize ipv4 bytes
-    pub fn init_bytes(&mut self, container: &mut [u8]) {
-        container[0] = self.echo_type;
-        container[1] = self.code;
-        container[4] = self.identifier[0];
-        container[5] = self.identifier[1];
-        container[8..].copy_from_slice(&self.echo_data[0..21]);
-        self.base_chcksm = self.calc_checksum(container);
+// socket2 only takes an address for IP_MULTICAST_IF; Linux also accepts `ip_mreqn`, which lets us
+// pick the outgoing interface by index.
+pub fn set_multicast_if_v4_index(socket: &Socket, index: u32) -> Result<(), RingError> {
+    let mreqn = libc::ip_mreqn {
+        imr_multiaddr: libc::in_addr { s_addr: 0 },
+        imr_address: libc::in_addr { s_addr: 0 },
+        imr_ifindex: index as libc::c_int,
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &mreqn as *const libc::ip_mreqn as *const libc::c_void,
+            mem::size_of::<libc::ip_mreqn>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(RingError::IoError(io::Error::last_os_error()));
     }
-    pub fn update_bytes(&mut self, final_bytes: &mut [u8]) {
-        self.update_chksm(final_bytes);
+    Ok(())
+}
+
+// Put the send time and the run nonce at the start of the payload (like iputils does with a
+// `struct timeval`), so a reply can be timed from its own bytes
+pub fn embed_time(payload: &mut [u8], sent: SystemTime, nonce: u32) {
+    let nanos = sent
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    payload[..8].copy_from_slice(&nanos.to_be_bytes());
+    payload[8..EMBED_LENGTH].copy_from_slice(&nonce.to_be_bytes());
+}
+
+// Nanoseconds since the epoch plus a 32 bit nonce
+pub const EMBED_LENGTH: usize = 12;
+
+// Read back what `embed_time` wrote from the payload of an echo reply
+pub fn read_embedded(payload: &[u8]) -> Option<(SystemTime, u32)> {
+    if payload.len() < EMBED_LENGTH {
+        return None;
     }
+    let mut nanos = [0; 8];
+    nanos.copy_from_slice(&payload[..8]);
+    let mut nonce = [0; 4];
+    nonce.copy_from_slice(&payload[8..EMBED_LENGTH]);
+    let sent = UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(nanos));
+    Some((sent, u32::from_be_bytes(nonce)))
+}
+
+// Tells this run's replies apart from those to an earlier ring that reused our identifier
+pub fn run_nonce() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    nanos ^ std::process::id().rotate_left(16)
 }
@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+use socket2::SockAddr;
+
+// Filled in by the lookup thread, which signals every answer
+type Found = Arc<(Mutex<HashMap<IpAddr, Option<String>>>, Condvar)>;
+
+// Reverse (PTR) lookups for the addresses that answer a ring. `getnameinfo` blocks for as long as
+// the DNS server takes, so lookups run on a thread of their own and never hold up the receiver:
+// lines print the bare address until the name is in. Replies keep coming from the same few hosts,
+// so every answer (including "no name") is cached.
+#[derive(Debug, Default)]
+pub struct Names {
+    enabled: bool,
+    found: Found,
+    asked: HashSet<IpAddr>,
+    // Started with the first lookup
+    lookups: Option<mpsc::Sender<IpAddr>>,
+}
+
+impl Names {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    // `name (addr)` when the address has a PTR record and lookups are on, the bare address
+    // otherwise, including while the lookup is still out
+    pub fn host(&mut self, ip: IpAddr) -> String {
+        if !self.enabled {
+            return ip.to_string();
+        }
+        let known = self.found.0.lock().unwrap().get(&ip).cloned();
+        match known {
+            Some(name) => show(ip, name),
+            None => {
+                self.ask(ip);
+                ip.to_string()
+            }
+        }
+    }
+
+    // Like `host`, but waits for the lookup. For the summary, once the ring is over.
+    pub fn host_now(&mut self, ip: IpAddr) -> String {
+        if !self.enabled {
+            return ip.to_string();
+        }
+        self.ask(ip);
+        let (found, answered) = &*self.found;
+        let found = answered
+            .wait_while(found.lock().unwrap(), |found| !found.contains_key(&ip))
+            .unwrap();
+        show(ip, found[&ip].clone())
+    }
+
+    fn ask(&mut self, ip: IpAddr) {
+        if !self.asked.insert(ip) {
+            return;
+        }
+        let lookups = self.lookups.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::channel::<IpAddr>();
+            let found = self.found.clone();
+            // Ends when `Names` goes away and the channel with it
+            thread::spawn(move || {
+                for ip in rx {
+                    let name = lookup(ip);
+                    found.0.lock().unwrap().insert(ip, name);
+                    found.1.notify_all();
+                }
+            });
+            tx
+        });
+        _ = lookups.send(ip);
+    }
+}
+
+fn show(ip: IpAddr, name: Option<String>) -> String {
+    match name {
+        Some(name) => format!("{name} ({ip})"),
+        None => ip.to_string(),
+    }
+}
+
+fn lookup(ip: IpAddr) -> Option<String> {
+    let addr = SockAddr::from(SocketAddr::new(ip, 0));
+    // NI_MAXHOST
+    let mut host = [0 as libc::c_char; 1025];
+    let res = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr(),
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+    // SAFETY: on success getnameinfo wrote a NUL terminated name into `host`
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    name.to_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn off_shows_addresses() {
+        let mut names = Names::new(false);
+        assert_eq!(names.host(LOCALHOST), "127.0.0.1");
+        assert_eq!(names.host_now(LOCALHOST), "127.0.0.1");
+    }
+
+    #[test]
+    fn lookups_dont_block() {
+        let mut names = Names::new(true);
+        let start = Instant::now();
+        assert_eq!(names.host(LOCALHOST), "127.0.0.1");
+        assert!(start.elapsed() < Duration::from_millis(100));
+        // Whatever the name is, it comes with the address, and is what `host` shows from then on
+        let name = names.host_now(LOCALHOST);
+        assert!(name.contains("127.0.0.1"), "{name}");
+        assert_eq!(names.host(LOCALHOST), name);
+    }
+}
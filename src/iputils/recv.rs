@@ -0,0 +1,155 @@
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+
+use socket2::{Domain, SockAddr, Socket};
+
+use super::tstamp::{self, Stamp};
+
+// What `recvmsg` tells us about a packet besides its bytes
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Received {
+    pub len: usize,
+    pub from: Option<SocketAddr>,
+    // The address the packet was sent to, IPv6 only. The ICMPv6 checksum covers it.
+    pub to: Option<IpAddr>,
+    // IPv4 TOS or IPv6 traffic class the packet arrived with
+    pub tos: Option<u8>,
+    // When the kernel (or NIC) saw the packet, if timestamping is on
+    pub stamp: Option<Stamp>,
+    // For TX stamps read from the error queue: which request they belong to, counted from 0 by the
+    // kernel (SOF_TIMESTAMPING_OPT_ID)
+    pub tx_id: Option<u32>,
+}
+
+// Room for every control message we ask for, with the kernel's alignment padding
+const CONTROL_LEN: usize = 256;
+
+// Ask the kernel to attach the TOS/traffic class of every received packet as ancillary data
+pub fn enable_recv_tos(socket: &Socket) -> io::Result<()> {
+    if socket.domain()? == Domain::IPV4 {
+        socket.set_recv_tos(true)
+    } else {
+        setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)
+    }
+}
+
+// Ask the kernel for the destination address of every received IPv6 packet, which the ICMPv6
+// checksum needs. IPv4 checksums don't, so it's a no-op there.
+pub fn enable_recv_dest(socket: &Socket) -> io::Result<()> {
+    if socket.domain()? == Domain::IPV4 {
+        return Ok(());
+    }
+    setsockopt_int(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)
+}
+
+fn setsockopt_int(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// `recvmsg(2)`, collecting the sender and the control messages enabled on the socket
+pub fn recv_msg(socket: &Socket, buf: &mut [u8]) -> io::Result<Received> {
+    recv_msg_with_flags(socket, buf, 0)
+}
+
+pub fn recv_msg_with_flags(
+    socket: &Socket,
+    buf: &mut [u8],
+    flags: libc::c_int,
+) -> io::Result<Received> {
+    let mut addr: MaybeUninit<libc::sockaddr_storage> = MaybeUninit::zeroed();
+    let mut control = [0u8; CONTROL_LEN];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = addr.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = CONTROL_LEN as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut received = Received {
+        len: len as usize,
+        ..Default::default()
+    };
+    if msg.msg_namelen > 0 {
+        // SAFETY: the kernel initialized `msg_namelen` bytes of the address
+        let addr = unsafe { SockAddr::new(addr.assume_init(), msg.msg_namelen) };
+        received.from = addr.as_socket();
+    }
+
+    // SAFETY: `msg` was filled in by a successful `recvmsg`, so the control buffer holds
+    // `msg_controllen` bytes of well formed control messages.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::IPPROTO_IP, libc::IP_TOS) => received.tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    let tclass = (data as *const libc::c_int).read_unaligned();
+                    received.tos = Some(tclass as u8);
+                }
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                    let info = (data as *const libc::in6_pktinfo).read_unaligned();
+                    received.to = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                }
+                (libc::SOL_SOCKET, libc::SCM_TIMESTAMPING) => {
+                    let ts = (data as *const [libc::timespec; 3]).read_unaligned();
+                    received.stamp = tstamp::from_timestamping(&ts);
+                }
+                (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) => {
+                    let err = (data as *const libc::sock_extended_err).read_unaligned();
+                    if err.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                        received.tx_id = Some(err.ee_data);
+                    }
+                }
+                (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                    let ts = (data as *const libc::timespec).read_unaligned();
+                    received.stamp = tstamp::from_timestampns(&ts);
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(received)
+}
+
+// Wait up to `timeout` for something to read, without reading it. `poll` reports anything on the
+// error queue (POLLERR) whether we ask or not, and that isn't readable with a plain `recvmsg`. It
+// stays until read with MSG_ERRQUEUE, so it ends the wait early every time until then.
+pub fn wait_readable(socket: &Socket, timeout: std::time::Duration) -> bool {
+    let mut pfd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut pfd, 1, timeout.as_millis() as libc::c_int) };
+    res > 0 && pfd.revents & libc::POLLIN != 0
+}
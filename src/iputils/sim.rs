@@ -0,0 +1,187 @@
+// A network that only exists in memory, for tests. It answers the echo requests sent through it the
+// way `fate` says and after the delay it says, with real ICMP bytes so the whole receive path is
+// exercised.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use socket2::SockAddr;
+
+use super::icmp::{self, IcmpPacket, Quote, Version};
+use super::recv::Received;
+use super::tstamp::Stamp;
+use super::wire::Wire;
+
+// What becomes of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    Reply(Duration),
+    Lost,
+    // Answered twice, the second time `Duration` after the first
+    Duplicate(Duration, Duration),
+    // Answered with a bit flipped on the way
+    Corrupt(Duration),
+    // A router on the way can't reach the destination
+    Unreachable(Duration),
+}
+
+// Our own address, which ICMPv6 checksums cover
+const LOCAL_V6: Ipv6Addr = Ipv6Addr::LOCALHOST;
+const ROUTER_V4: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+const ROUTER_V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0xfe));
+
+// Things that arrive some time from now, handed out in the order they come due. `SimNet` queues
+// ICMP messages on one, `MockTransport` whole events.
+pub struct Timed<T> {
+    queue: Mutex<Vec<(Instant, T)>>,
+    arrived: Condvar,
+}
+
+impl<T> Timed<T> {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(Vec::new()),
+            arrived: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, after: Duration, item: T) {
+        self.queue
+            .lock()
+            .unwrap()
+            .push((Instant::now() + after, item));
+        self.arrived.notify_all();
+    }
+
+    // Wait up to `timeout` for something to come due, without taking it
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next = queue.iter().map(|(due, _)| *due).min();
+            if next.is_some_and(|due| due <= now) {
+                return true;
+            }
+            if now >= deadline {
+                return false;
+            }
+            // Sleep until the next one is due, a new one is queued, or the timeout
+            let until = next.map_or(deadline, |due| due.min(deadline));
+            queue = self.arrived.wait_timeout(queue, until - now).unwrap().0;
+        }
+    }
+
+    // The first thing that is due, if anything is
+    pub fn pop(&self) -> Option<T> {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let next = (0..queue.len())
+            .filter(|&i| queue[i].0 <= now)
+            .min_by_key(|&i| queue[i].0)?;
+        Some(queue.remove(next).1)
+    }
+}
+
+struct Delivery {
+    bytes: Vec<u8>,
+    from: SocketAddr,
+}
+
+pub struct SimNet {
+    fate: Box<dyn Fn(u16) -> Fate + Send + Sync>,
+    queue: Timed<Delivery>,
+}
+
+impl SimNet {
+    // `fate` gets the sequence on the wire of every request
+    pub fn new(fate: impl Fn(u16) -> Fate + Send + Sync + 'static) -> Self {
+        Self {
+            fate: Box::new(fate),
+            queue: Timed::new(),
+        }
+    }
+
+    fn deliver(&self, after: Duration, bytes: Vec<u8>, from: IpAddr) {
+        let from = SocketAddr::new(from, 0);
+        self.queue.push(after, Delivery { bytes, from });
+    }
+}
+
+fn pseudo(from: IpAddr, len: usize) -> u32 {
+    match from {
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(from) => icmp::pseudo_sum(&from, &LOCAL_V6, len),
+    }
+}
+
+impl Wire for SimNet {
+    fn send_to(&self, packet: &[u8], to: &SockAddr) -> io::Result<usize> {
+        let dest = to
+            .as_socket()
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?
+            .ip();
+        let (version, router) = match dest {
+            IpAddr::V4(_) => (Version::V4, ROUTER_V4),
+            IpAddr::V6(_) => (Version::V6, ROUTER_V6),
+        };
+        let request = match IcmpPacket::parse(packet, version) {
+            Ok(IcmpPacket::EchoRequest(request)) => request,
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let reply = IcmpPacket::EchoReply(request).serialize(version, pseudo(dest, packet.len()));
+        match (self.fate)(request.seq) {
+            Fate::Reply(after) => self.deliver(after, reply, dest),
+            Fate::Lost => {}
+            Fate::Duplicate(after, again) => {
+                self.deliver(after, reply.clone(), dest);
+                self.deliver(after + again, reply, dest);
+            }
+            Fate::Corrupt(after) => {
+                let mut reply = reply;
+                *reply.last_mut().unwrap() ^= 0x01;
+                self.deliver(after, reply, dest);
+            }
+            Fate::Unreachable(after) => {
+                // The router quotes the IP header of the request and the request itself
+                let mut datagram = match version {
+                    Version::V4 => vec![0x45; 20],
+                    Version::V6 => vec![0x60; 40],
+                };
+                datagram.extend_from_slice(packet);
+                let error = IcmpPacket::DestinationUnreachable(Quote {
+                    code: 1,
+                    datagram: &datagram,
+                });
+                let len = datagram.len() + icmp::HEADER_LEN;
+                self.deliver(after, error.serialize(version, pseudo(router, len)), router);
+            }
+        }
+        Ok(packet.len())
+    }
+    fn tx_stamps(&self, _: Option<u32>) -> Vec<(u32, Stamp)> {
+        Vec::new()
+    }
+    fn connect(&self, _: &SockAddr) -> io::Result<()> {
+        Ok(())
+    }
+    fn wait_readable(&self, timeout: Duration) -> bool {
+        self.queue.wait(timeout)
+    }
+    fn recv_msg(&self, buf: &mut [u8]) -> io::Result<Received> {
+        let delivery = self
+            .queue
+            .pop()
+            .ok_or(io::Error::from(io::ErrorKind::WouldBlock))?;
+        let len = delivery.bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&delivery.bytes[..len]);
+        Ok(Received {
+            len,
+            from: Some(delivery.from),
+            to: delivery.from.is_ipv6().then_some(IpAddr::V6(LOCAL_V6)),
+            ..Default::default()
+        })
+    }
+}
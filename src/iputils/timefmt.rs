@@ -0,0 +1,118 @@
+// Timestamps in front of every reply, timeout and error line (-D, --timestamp), to line rings up
+// with syslog and incident timelines.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // Seconds since the Unix epoch with microseconds, like iputils' -D
+    Epoch,
+    // UTC date and time, e.g. 2024-05-01T12:00:00.123456Z
+    Iso8601,
+    // Seconds since the ring started
+    Relative,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "epoch" | "unix" => Some(Format::Epoch),
+            "iso8601" | "iso" => Some(Format::Iso8601),
+            "relative" => Some(Format::Relative),
+            _ => None,
+        }
+    }
+
+    // The prefix for a line printed now, `start` being when the ring started
+    pub fn prefix(&self, start: Instant) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        match self {
+            Format::Epoch => format!("[{}.{:06}] ", now.as_secs(), now.subsec_micros()),
+            Format::Iso8601 => format!("[{}] ", iso8601(now.as_secs(), now.subsec_micros())),
+            Format::Relative => {
+                let since = start.elapsed();
+                format!("[+{}.{:06}] ", since.as_secs(), since.subsec_micros())
+            }
+        }
+    }
+}
+
+// Empty when no format was asked for, so it can go in front of any line
+pub fn prefix(format: Option<Format>, start: Instant) -> String {
+    format.map(|f| f.prefix(start)).unwrap_or_default()
+}
+
+fn iso8601(secs: u64, micros: u32) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        micros
+    )
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian (year, month, day). Howard Hinnant's algorithm,
+// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch() {
+        assert_eq!(iso8601(0, 0), "1970-01-01T00:00:00.000000Z");
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(iso8601(951782400, 0), "2000-02-29T00:00:00.000000Z");
+        assert_eq!(iso8601(951868800, 0), "2000-03-01T00:00:00.000000Z");
+        // Divisible by 100 but not by 400, so no February 29th
+        assert_eq!(iso8601(4107542400, 0), "2100-03-01T00:00:00.000000Z");
+    }
+
+    #[test]
+    fn end_of_year() {
+        assert_eq!(iso8601(1735689599, 0), "2024-12-31T23:59:59.000000Z");
+        assert_eq!(iso8601(1735689600, 0), "2025-01-01T00:00:00.000000Z");
+    }
+
+    #[test]
+    fn microseconds_are_zero_padded() {
+        assert_eq!(iso8601(0, 42), "1970-01-01T00:00:00.000042Z");
+        assert_eq!(iso8601(0, 999_999), "1970-01-01T00:00:00.999999Z");
+    }
+
+    #[test]
+    fn days_before_the_epoch() {
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn parses_format_names() {
+        assert_eq!(Format::parse("iso"), Some(Format::Iso8601));
+        assert_eq!(Format::parse("unix"), Some(Format::Epoch));
+        assert_eq!(Format::parse("relative"), Some(Format::Relative));
+        assert_eq!(Format::parse("rfc3339"), None);
+    }
+}
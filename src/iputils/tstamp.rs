@@ -0,0 +1,290 @@
+use std::fmt::Display;
+use std::io;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use socket2::Socket;
+
+use super::recv;
+
+// Where a send or receive time came from, from least to most accurate. `Payload` is the send time a
+// reply carried back in its payload (--embed-time), and `User` our own clock reading around
+// `send`/`recv`; both include syscall and thread scheduling latency. The kernel
+// stamps packets as they leave or enter the network stack, and NICs with hardware timestamping
+// stamp them on the wire.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum Source {
+    Payload,
+    User,
+    Kernel,
+    Hardware,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Payload => write!(f, "payload"),
+            Source::User => write!(f, "user"),
+            Source::Kernel => write!(f, "kernel"),
+            Source::Hardware => write!(f, "hardware"),
+        }
+    }
+}
+
+// When a packet left or arrived. `time` is on the system clock, like our own readings around
+// `send`/`recv`. `hardware` is the NIC's stamp, which runs on the NIC's own clock (its PHC) and can
+// only be compared with other hardware stamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub time: SystemTime,
+    // Who read `time`: the kernel, or us
+    pub source: Source,
+    pub hardware: Option<SystemTime>,
+}
+
+impl Stamp {
+    pub fn user(time: SystemTime) -> Self {
+        Stamp {
+            time,
+            source: Source::User,
+            hardware: None,
+        }
+    }
+
+    // Two readings of the same moment: the system clock time of the more accurate one, and the
+    // NIC's stamp from whichever has it
+    pub fn merge(self, other: Stamp) -> Stamp {
+        let (best, rest) = match other.source > self.source {
+            true => (other, self),
+            false => (self, other),
+        };
+        Stamp {
+            hardware: best.hardware.or(rest.hardware),
+            ..best
+        }
+    }
+}
+
+// How long from `sent` to `received`, and which clocks timed each end. NIC stamps are only paired
+// with each other, everything else is on the system clock.
+pub fn between(sent: &Stamp, received: &Stamp) -> Option<(Duration, Source, Source)> {
+    if let (Some(tx), Some(rx)) = (sent.hardware, received.hardware) {
+        if let Ok(rtt) = rx.duration_since(tx) {
+            return Some((rtt, Source::Hardware, Source::Hardware));
+        }
+    }
+    let rtt = received.time.duration_since(sent.time).ok()?;
+    Some((rtt, sent.source, received.source))
+}
+
+// Ask for software and (if the NIC has it switched on) hardware timestamps on both directions.
+// TX stamps come back on the socket's error queue without the packet attached.
+const TIMESTAMPING_FLAGS: libc::c_uint = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+    | libc::SOF_TIMESTAMPING_SOFTWARE
+    | libc::SOF_TIMESTAMPING_TX_HARDWARE
+    | libc::SOF_TIMESTAMPING_RX_HARDWARE
+    | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+    | libc::SOF_TIMESTAMPING_OPT_TSONLY
+    // Number every request, so a stamp can't be mistaken for the one of the request before it
+    | libc::SOF_TIMESTAMPING_OPT_ID;
+
+// How long we wait for the kernel to hand back the TX stamp of a request we just sent
+const TX_WAIT: Duration = Duration::from_millis(5);
+const TX_POLL: Duration = Duration::from_micros(50);
+
+// Turn on kernel timestamping. Returns whether TX stamps will be available; older kernels and
+// some socket types only support the receive side (SO_TIMESTAMPNS).
+pub fn enable(socket: &Socket) -> bool {
+    let fd = socket.as_raw_fd();
+    let flags = TIMESTAMPING_FLAGS;
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &flags as *const libc::c_uint as *const libc::c_void,
+            mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        return true;
+    }
+    let on: libc::c_int = 1;
+    unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+    false
+}
+
+// Read the TX stamps on the socket's error queue, by the id of their request (the number of
+// requests sent on the socket before it). With `wait_for`, poll for a short while until that
+// request's kernel stamp is in; it is taken when the packet hits the device, right after `send`.
+// The NIC's stamp may come later, or never, so it isn't waited for.
+pub fn read_tx(socket: &Socket, wait_for: Option<u32>) -> Vec<(u32, Stamp)> {
+    let mut buf = [0u8; 64];
+    let mut found: Vec<(u32, Stamp)> = Vec::new();
+    let start = std::time::Instant::now();
+    loop {
+        match recv::recv_msg_with_flags(socket, &mut buf, libc::MSG_ERRQUEUE) {
+            // The kernel and the NIC stamp a request in separate messages
+            Ok(r) => {
+                let (Some(id), Some(stamp)) = (r.tx_id, r.stamp) else {
+                    // Something else was queued (an ICMP error with IP_RECVERR), keep looking
+                    continue;
+                };
+                match found.iter_mut().find(|(i, _)| *i == id) {
+                    Some((_, f)) => *f = f.merge(stamp),
+                    None => found.push((id, stamp)),
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && start.elapsed() < TX_WAIT => {
+                let waiting = wait_for.is_some_and(|id| {
+                    !found
+                        .iter()
+                        .any(|(i, f)| *i == id && f.source == Source::Kernel)
+                });
+                if !waiting {
+                    return found;
+                }
+                thread::sleep(TX_POLL)
+            }
+            Err(_) => return found,
+        }
+    }
+}
+
+fn to_system_time(ts: &libc::timespec) -> Option<SystemTime> {
+    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+// SCM_TIMESTAMPING carries three timespecs: software, (deprecated) transformed hardware and raw
+// hardware. Keep the raw hardware one next to the software one when the NIC filled it in.
+pub fn from_timestamping(ts: &[libc::timespec; 3]) -> Option<Stamp> {
+    let hardware = to_system_time(&ts[2]);
+    match to_system_time(&ts[0]) {
+        Some(time) => Some(Stamp {
+            time,
+            source: Source::Kernel,
+            hardware,
+        }),
+        // Only the NIC stamped it, so the system clock reading is ours
+        None => hardware.map(|_| Stamp {
+            hardware,
+            ..Stamp::user(SystemTime::now())
+        }),
+    }
+}
+
+pub fn from_timestampns(ts: &libc::timespec) -> Option<Stamp> {
+    to_system_time(ts).map(|time| Stamp {
+        time,
+        source: Source::Kernel,
+        hardware: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    fn kernel(ms: u64, hardware: Option<u64>) -> Stamp {
+        Stamp {
+            time: at(ms),
+            source: Source::Kernel,
+            hardware: hardware.map(at),
+        }
+    }
+
+    #[test]
+    fn hardware_pairs_with_hardware() {
+        // The NIC's clock is way off the system clock, which doesn't matter when both ends use it
+        let rtt = between(&kernel(1000, Some(50)), &kernel(1012, Some(60)));
+        assert_eq!(
+            rtt,
+            Some((
+                Duration::from_millis(10),
+                Source::Hardware,
+                Source::Hardware
+            ))
+        );
+    }
+
+    #[test]
+    fn hardware_never_pairs_with_the_system_clock() {
+        let sent = Stamp::user(at(1000));
+        let rtt = between(&sent, &kernel(1012, Some(60)));
+        assert_eq!(
+            rtt,
+            Some((Duration::from_millis(12), Source::User, Source::Kernel))
+        );
+        let rtt = between(&kernel(1000, Some(50)), &kernel(1012, None));
+        assert_eq!(
+            rtt,
+            Some((Duration::from_millis(12), Source::Kernel, Source::Kernel))
+        );
+    }
+
+    #[test]
+    fn tx_stamps_belong_to_their_request() {
+        use socket2::{Domain, Type};
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        socket.connect(&peer.local_addr().unwrap().into()).unwrap();
+        if !enable(&socket) {
+            return;
+        }
+        socket.set_nonblocking(true).unwrap();
+        // Request 0's stamp is left on the queue, like after a read that gave up
+        socket.send(b"0").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let before = SystemTime::now();
+        socket.send(b"1").unwrap();
+        let stamps = read_tx(&socket, Some(1));
+        let stamp = stamps
+            .iter()
+            .find(|(id, _)| *id == 1)
+            .expect("a TX stamp")
+            .1;
+        assert!(stamp.time >= before, "got the stamp of request 0");
+        // Request 0's is handed back too, under its own id
+        let late = stamps
+            .iter()
+            .find(|(id, _)| *id == 0)
+            .expect("request 0's stamp")
+            .1;
+        assert!(late.time < before);
+        assert!(read_tx(&socket, None).is_empty());
+    }
+
+    #[test]
+    fn merged_stamps_keep_the_best_of_both() {
+        // The NIC's message comes without a system clock reading of its own
+        let nic = Stamp {
+            hardware: Some(at(50)),
+            ..Stamp::user(at(2000))
+        };
+        assert_eq!(kernel(1000, None).merge(nic), kernel(1000, Some(50)));
+        assert_eq!(nic.merge(kernel(1000, None)), kernel(1000, Some(50)));
+    }
+
+    #[test]
+    fn reversed_clocks() {
+        assert_eq!(between(&kernel(1000, None), &kernel(990, None)), None);
+    }
+}
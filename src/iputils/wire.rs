@@ -0,0 +1,39 @@
+// What a ring does with its socket once it is set up: send requests, and wait for and read what
+// comes back. Behind a trait so tests can ring a simulated network (see `sim`) instead.
+
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockAddr, Socket};
+
+use super::recv::{self, Received};
+use super::tstamp::{self, Stamp};
+
+pub trait Wire: Send + Sync {
+    fn send_to(&self, packet: &[u8], to: &SockAddr) -> io::Result<usize>;
+    // The TX stamps that came back so far, by request id (the number of requests sent before
+    // it), if timestamping said it would tell us. Waits a little for `wait_for`'s.
+    fn tx_stamps(&self, wait_for: Option<u32>) -> Vec<(u32, Stamp)>;
+    fn connect(&self, to: &SockAddr) -> io::Result<()>;
+    // Wait up to `timeout` for something to read, without reading it
+    fn wait_readable(&self, timeout: Duration) -> bool;
+    fn recv_msg(&self, buf: &mut [u8]) -> io::Result<Received>;
+}
+
+impl Wire for Socket {
+    fn send_to(&self, packet: &[u8], to: &SockAddr) -> io::Result<usize> {
+        Socket::send_to(self, packet, to)
+    }
+    fn tx_stamps(&self, wait_for: Option<u32>) -> Vec<(u32, Stamp)> {
+        tstamp::read_tx(self, wait_for)
+    }
+    fn connect(&self, to: &SockAddr) -> io::Result<()> {
+        Socket::connect(self, to)
+    }
+    fn wait_readable(&self, timeout: Duration) -> bool {
+        recv::wait_readable(self, timeout)
+    }
+    fn recv_msg(&self, buf: &mut [u8]) -> io::Result<Received> {
+        recv::recv_msg(self, buf)
+    }
+}
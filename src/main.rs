@@ -1,9 +1,15 @@
 mod error;
+mod resolver;
 mod ring_impl;
+mod transport;
 use error::RingError;
 use getopts::Options;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::{env, net::ToSocketAddrs};
+use socket2::{Domain, SockAddr, Socket};
+use std::{
+    env, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    thread,
+};
 mod iputils;
 
 // TODO: Build the help message, with colors too
@@ -17,11 +23,31 @@ Options:
 -4, --ipv4        Ring IPV4 host
 -6, --ipv6        Ring IPV6 host
 -b, --broadcast   Enable ringing broadcast address
+-I, --interface   Ring from source address or interface <addr|iface>
+    --netns       Ring from inside network namespace <name|path>
+    --vrf         Ring through the routing table of VRF <device>
+-r, --raw         Use a raw socket (needs CAP_NET_RAW)
+    --tcp         Ring TCP port <port> with handshakes instead of ICMP echo requests
+    --mcast-if    Send multicast rings out of interface <iface|addr>
 -c, --count       Ring <n> times
 -i, --interval    Ring every <n> seconds
+-l, --preload     Send <n> requests back to back before ringing every interval
 -d, --timeout     Wait atmost <n> seconds for echo replies
 -q, --quiet       Don't print intermediate ring results
+-D                Start every line with the Unix time, like --timestamp=epoch
+    --timestamp   Start every line with the time as <epoch|iso8601|relative>
+    --stats-every Print the stats so far every <n> seconds (Ctrl + \\ prints them once)
+-n, --numeric     Don't use DNS at all, the destination must be an address
+    --resolver    Look the destination up at DNS server <addr[:port]> instead of the system resolver
+    --reresolve   Look the destination up again every <n> seconds and follow address changes
+    --nth         Ring the <n>th resolved address of the destination
+    --all-addresses Ring every resolved address of the destination, one after another
+    --compare     Ring every resolved address at once and compare their loss and RTT
+    --rdns        Show the names (PTR records) of hosts that answer, once looked up
+    --show-tstamp Show where each reply's send and receive timestamps came from
+    --embed-time  Carry the send time in each request, to time late and out-of-order replies
 -t, --ttl         Set time-to-live value
+-Q, --tos         Mark requests with TOS/traffic class <n> or DSCP <name>
 
 Arguments:
     \x1b[1;31m<destination_host>\x1b[0m
@@ -34,16 +60,50 @@ See ring(1).";
 const VERSION: &str = "0.2";
 
 pub(crate) const DATA: &[u8; 21] = b"SWIKISSSWIKISSSWIKISS"; // sweetkiss
-pub(crate) const DATA_LENGTH: usize = 8 + DATA.len(); // fixed 8 bytes data field
 
 struct RingOptions {
     socket: Socket,
     count: i64,
-    ttl: u32,
+    // Only applied when asked for (-t), otherwise the kernel default stays
+    ttl: Option<u32>,
     interval: u64,
+    // Put a timestamp in front of every reply, timeout and error (-D, --timestamp)
+    timestamp: Option<iputils::timefmt::Format>,
+    // Print the stats so far every <n> seconds
+    stats_every: Option<u64>,
+    // Requests sent back to back before the interval kicks in (-l)
+    preload: u32,
     timeout: u128,
     quite: bool,
+    // One of several parallel rings (--compare), which only report through the final table
+    compare: bool,
     broadcast: bool,
+    // Destination is a multicast group, so more than one host may answer
+    multicast: bool,
+    mcast_if: Option<String>,
+    // Source address or interface to send from (-I)
+    interface: Option<String>,
+    // Put the send time and `nonce` in the payload of every request
+    embed_time: bool,
+    // Random per run, so replies to an earlier ring can be told apart
+    nonce: u32,
+    // Look up the names of the hosts that answer us
+    rdns: bool,
+    // Print which clock (user, kernel, hardware) timed each reply
+    show_tstamp: bool,
+    // TOS byte (IPv4) or traffic class (IPv6) our requests are marked with
+    tos: Option<u8>,
+    // VRF master device whose routing table the ring should use
+    vrf: Option<String>,
+    // DNS server given with --resolver, also used when re-resolving
+    resolver: Option<SocketAddr>,
+    // Look the destination up again every <n> seconds, and follow it when its address changes
+    reresolve: Option<u64>,
+    // `SOCK_RAW` instead of `SOCK_DGRAM`. Replies come with their IPv4 header attached.
+    raw: bool,
+    // Ring this TCP port with handshakes instead of sending echo requests (--tcp). `socket` is then
+    // a UDP socket that only picks the route.
+    tcp: Option<u16>,
     addr: String,
 }
 
@@ -64,50 +124,320 @@ impl From<&str> for IP {
 }
 
 impl RingOptions {
-    fn new() -> Result<Self, RingError> {
-        Ok(Self {
-            socket: Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::ICMPV6))?,
-            count: -1,
-            ttl: 128,
-            interval: 1,
-            timeout: 1000,
-            quite: false,
-            broadcast: false,
-            addr: String::new(),
-        })
+    fn new(raw: bool, tcp: Option<u16>) -> Result<Self, RingError> {
+        Self::open(IP::V6, raw, tcp)
+    }
+    fn new_ip4(raw: bool, tcp: Option<u16>) -> Result<Self, RingError> {
+        Self::open(IP::V4, raw, tcp)
+    }
+    fn open(ip: IP, raw: bool, tcp: Option<u16>) -> Result<Self, RingError> {
+        let (socket, raw) = open_socket(ip, raw, tcp)?;
+        let mut opts = Self::with_socket(socket, raw);
+        opts.tcp = tcp;
+        Ok(opts)
     }
-    fn new_ip4() -> Result<Self, RingError> {
-        Ok(Self {
-            socket: Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?,
+    fn with_socket(socket: Socket, raw: bool) -> Self {
+        Self {
+            socket,
             count: -1,
-            ttl: 128,
+            ttl: None,
             interval: 1,
+            preload: 1,
+            stats_every: None,
+            timestamp: None,
             timeout: 1000,
             quite: false,
+            compare: false,
             broadcast: false,
+            multicast: false,
+            mcast_if: None,
+            interface: None,
+            vrf: None,
+            resolver: None,
+            reresolve: None,
+            tos: None,
+            show_tstamp: false,
+            rdns: false,
+            embed_time: false,
+            nonce: iputils::run_nonce(),
+            raw,
+            tcp: None,
             addr: String::new(),
-        })
+        }
     }
     fn set_count(&mut self, count: i64) {
         self.count = count;
     }
     fn set_ttl(&mut self, ttl: u32) -> Result<(), RingError> {
-        self.socket.set_ttl(ttl)?;
-        self.ttl = ttl;
+        let v4 = self.socket.domain()? == Domain::IPV4;
+        if v4 {
+            self.socket.set_ttl(ttl)?;
+        } else {
+            self.socket.set_unicast_hops_v6(ttl)?;
+        }
+        if self.multicast {
+            // Multicast has its own hop limit, which defaults to 1
+            if v4 {
+                self.socket.set_multicast_ttl_v4(ttl)?;
+            } else {
+                self.socket.set_multicast_hops_v6(ttl)?;
+            }
+        }
+        self.ttl = Some(ttl);
+        Ok(())
+    }
+    fn set_tos(&mut self, tos: u8) -> Result<(), RingError> {
+        if self.socket.domain()? == Domain::IPV4 {
+            self.socket.set_tos(tos as u32)?;
+        } else {
+            self.socket.set_tclass_v6(tos as u32)?;
+        }
+        self.tos = Some(tos);
         Ok(())
     }
     fn set_quite(&mut self, quite: bool) {
         self.quite = quite;
     }
-    fn set_ipv(&mut self, ip: Option<IP>) -> Result<(), RingError> {
-        if ip == Some(IP::V4) {
-            self.socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?;
-            self.set_ttl(self.ttl)?;
-            self.socket.set_broadcast(self.broadcast)?;
+    fn family(&self) -> Result<IP, RingError> {
+        Ok(match self.socket.domain()? {
+            Domain::IPV4 => IP::V4,
+            _ => IP::V6,
+        })
+    }
+    // Same options on a new, unbound socket of family `ip`
+    fn with_family(&self, ip: IP) -> Result<Self, RingError> {
+        let (socket, raw) = open_socket(ip, self.raw, self.tcp)?;
+        let mut opt = Self {
+            socket,
+            multicast: false,
+            mcast_if: self.mcast_if.clone(),
+            interface: self.interface.clone(),
+            vrf: self.vrf.clone(),
+            addr: self.addr.clone(),
+            raw,
+            ..*self
+        };
+        if let Some(ttl) = self.ttl {
+            opt.set_ttl(ttl)?;
+        }
+        if let Some(tos) = self.tos {
+            opt.set_tos(tos)?;
         }
+        opt.socket.set_broadcast(self.broadcast)?;
+        Ok(opt)
+    }
+    fn reopen(&mut self, ip: IP) -> Result<(), RingError> {
+        *self = self.with_family(ip)?;
         Ok(())
     }
 }
+
+fn open_socket(ip: IP, raw: bool, tcp: Option<u16>) -> Result<(Socket, bool), RingError> {
+    match tcp {
+        Some(_) => Ok((iputils::route_socket(ip)?, false)),
+        None => iputils::open_socket(ip, raw),
+    }
+}
+
+// Broadcast and multicast destinations can be answered by many hosts. Point the socket at the
+// right interface before connecting and mark the ring so every reply gets collected.
+fn setup_one_to_many(opt: &mut RingOptions, addr: &mut SocketAddr) -> Result<(), RingError> {
+    if !iputils::is_one_to_many(addr) {
+        return Ok(());
+    }
+    if opt.tcp.is_some() {
+        eprintln!(
+            "\n\x1b[1;31mError: {} is a broadcast or multicast address, which --tcp can't ring\x1b[0m",
+            addr.ip()
+        );
+        return Err(RingError::ArgError);
+    }
+    opt.multicast = addr.ip().is_multicast();
+    // -t covers the multicast hop limit as well
+    if let Some(ttl) = opt.ttl {
+        opt.set_ttl(ttl)?;
+    }
+    if !opt.multicast && !opt.broadcast {
+        eprintln!(
+            "\n\x1b[1;31mError: {} is a broadcast address, use -b to ring it\x1b[0m",
+            addr.ip()
+        );
+        return Err(RingError::ArgError);
+    }
+    let link_local = iputils::is_link_local_v6(addr);
+    // Without --mcast-if, multicast goes out of the -I interface like in iputils
+    let mcast_if = opt.mcast_if.as_ref().or(opt.interface.as_ref());
+    match addr {
+        SocketAddr::V4(_) => {
+            if let Some(iface) = mcast_if {
+                match iface.parse::<Ipv4Addr>() {
+                    Ok(a) => opt.socket.set_multicast_if_v4(&a)?,
+                    Err(_) => {
+                        iputils::set_multicast_if_v4_index(&opt.socket, iputils::if_index(iface)?)?
+                    }
+                }
+            }
+        }
+        SocketAddr::V6(a) => {
+            let index = match mcast_if {
+                Some(iface) if iface.parse::<IpAddr>().is_err() => {
+                    Some(match iface.parse::<u32>() {
+                        Ok(i) => i,
+                        Err(_) => iputils::if_index(iface)?,
+                    })
+                }
+                _ => None,
+            };
+            if let Some(i) = index {
+                opt.socket.set_multicast_if_v6(i)?;
+                if a.scope_id() == 0 {
+                    a.set_scope_id(i);
+                }
+            } else if link_local && a.scope_id() == 0 {
+                eprintln!("\n\x1b[1;31mError: link-local multicast needs an interface, use --mcast-if <iface>\x1b[0m");
+                return Err(RingError::ArgError);
+            }
+        }
+    }
+    println!("\n\x1b[1;33m[WARNING]: Ringing a one-to-many address, collecting replies from every host\x1b[0m");
+    Ok(())
+}
+
+// Pin the ring to a source address or an interface (-I). Has to happen before `connect`, which
+// would otherwise pick both from the routing table.
+fn bind_interface(opt: &RingOptions, addr: &mut SocketAddr) -> Result<(), RingError> {
+    // Binding to the VRF device makes the kernel route through the VRF's table. A source address
+    // from -I still applies on top of it.
+    if let Some(vrf) = &opt.vrf {
+        if let Err(e) = opt.socket.bind_device(Some(vrf.as_bytes())) {
+            eprintln!("\n\x1b[1;31mError: Can't ring through VRF {vrf}: {e}\x1b[0m");
+            return Err(RingError::IoError(e));
+        }
+    }
+    if let Some(iface) = &opt.interface {
+        if let Ok(ip) = iface.parse::<IpAddr>() {
+            if let Err(e) = opt.socket.bind(&SockAddr::from(SocketAddr::new(ip, 0))) {
+                eprintln!("\n\x1b[1;31mError: Can't ring from {ip}: {e}\x1b[0m");
+                return Err(RingError::IoError(e));
+            }
+        } else {
+            let index = iputils::if_index(iface)?;
+            if let Err(e) = opt.socket.bind_device(Some(iface.as_bytes())) {
+                eprintln!("\n\x1b[1;31mError: Can't ring from {iface}: {e}\x1b[0m");
+                return Err(RingError::IoError(e));
+            }
+            if let SocketAddr::V6(a) = addr {
+                if a.scope_id() == 0 && iputils::is_link_local_v6(&SocketAddr::V6(*a)) {
+                    a.set_scope_id(index);
+                }
+            }
+        }
+    }
+    if let SocketAddr::V6(a) = addr {
+        if a.scope_id() == 0 && iputils::is_link_local_v6(&SocketAddr::V6(*a)) {
+            eprintln!("\n\x1b[1;31mError: {} is link-local, add the interface like {}%eth0 or use -I <iface>\x1b[0m", a.ip(), a.ip());
+            return Err(RingError::ArgError);
+        }
+    }
+    Ok(())
+}
+
+// Connected raw sockets only see packets coming from the peer, which would hide every responder of
+// a one-to-many ring. Those rings stay unconnected and always send to the group address.
+fn connect(opt: &RingOptions, addr: SocketAddr) -> io::Result<()> {
+    if opt.raw && (opt.broadcast || opt.multicast) {
+        return Ok(());
+    }
+    opt.socket.connect(&SockAddr::from(addr))
+}
+
+// Everything the socket needs before the first request to `addr` goes out. Only a failing
+// `connect` (no route, address family disabled) is worth trying the next address for.
+fn prepare(opt: &mut RingOptions, addr: &mut SocketAddr) -> Result<Option<io::Error>, RingError> {
+    let ip = resolver::family_of(addr);
+    if opt.family()? != ip {
+        opt.reopen(ip)?;
+    }
+    setup_one_to_many(opt, addr)?;
+    bind_interface(opt, addr)?;
+    Ok(connect(opt, *addr).err())
+}
+
+// --compare: ring every address at the same time, each from its own socket and thread, and put
+// their results side by side once all of them are done
+fn compare_addresses(
+    opt: &RingOptions,
+    candidates: Vec<SocketAddr>,
+    url: &str,
+    stop: ring_impl::Interrupt,
+) -> Result<(), RingError> {
+    let mut rings = Vec::new();
+    for candidate in candidates {
+        let mut o = opt
+            .with_family(resolver::family_of(&candidate))
+            .map_err(report)?;
+        o.quite = true;
+        o.compare = true;
+        let mut addr = candidate;
+        if let Some(e) = prepare(&mut o, &mut addr)? {
+            eprintln!("\n\x1b[1;31mError: Can't ring {addr}: {e}\x1b[0m");
+            continue;
+        }
+        let stop = stop.clone();
+        rings.push((addr, thread::spawn(move || ring(o, addr, stop))));
+    }
+    println!(
+        "\n\x1b[1;32mRinging \x1b[0m\x1b[4;34m{}\x1b[0m \x1b[1;32mat \x1b[1;37m{} addresses\x1b[0m\x1b[1;32m with {}\x1b[0m",
+        url,
+        rings.len(),
+        probes(opt.tcp)
+    );
+    if opt.count < 0 {
+        println!("\x1b[1;33m[NOTE]: Press CTRL + C to stop and compare, or use -c <n>\x1b[0m");
+    }
+    let mut summaries = Vec::new();
+    for (addr, ring) in rings {
+        match ring.join() {
+            Ok(Ok(summary)) => summaries.push(summary),
+            Ok(Err(e)) => eprintln!("Error: {addr}: {e}"),
+            Err(_) => eprintln!("Error: {addr}: ring panicked"),
+        }
+    }
+    ring_impl::print_comparison(&summaries);
+    Ok(())
+}
+
+// What every request is, for the banners
+fn probes(tcp: Option<u16>) -> String {
+    match tcp {
+        Some(port) => format!("\x1b[1;37mTCP handshakes\x1b[0m\x1b[1;32m to port {}", port),
+        None => format!("\x1b[1;37m{} bytes\x1b[0m\x1b[1;32m of data", DATA.len()),
+    }
+}
+
+// Ring `addr` with whatever the options ask for (ICMP echo, or TCP handshakes with --tcp)
+fn ring(
+    opt: RingOptions,
+    addr: SocketAddr,
+    stop: ring_impl::Interrupt,
+) -> Result<ring_impl::Summary, RingError> {
+    let transport = transport::open(&opt, addr)?;
+    ring_impl::run(opt, addr, stop, transport)
+}
+
+fn print_banner(url: &str, addr: SocketAddr, tcp: Option<u16>) {
+    if addr.ip().is_loopback() {
+        println!("\n\x1b[1;33m[WARNING]: Ringing a loopback address\x1b[0m");
+    }
+    println!(
+        // Terminal Color(VT100) Specification form (https://chrisyeh96.github.io/2020/03/28/terminal-colors.html)
+        "\n\x1b[1;32mRinging \x1b[0m\x1b[4;34m{}({})\x1b[0m \x1b[1;32mwith {}\x1b[0m\n",
+        url,
+        addr,
+        probes(tcp)
+    );
+}
+
 // TODO: Add more cli options like choosing between IP modes
 // and number of pings
 
@@ -124,6 +454,12 @@ fn print_help_brief(pname: &str) {
     println!("Usage:\n{} [options] <destination_address>", pname);
 }
 
+// Errors that need more than one line of explanation are printed before bailing out
+fn report(e: RingError) -> RingError {
+    eprintln!("\n{e}");
+    e
+}
+
 fn print_version(pname: &str) {
     println!("\x1b[1;33m{}: Version {}\x1b[0m", pname, VERSION);
 }
@@ -145,19 +481,131 @@ fn main() -> Result<(), RingError> {
         "quite",
         "Ring quitely without printing intermediate ping results",
     );
+    opts.optflag(
+        "n",
+        "numeric",
+        "Don't use DNS at all, the destination must be an address",
+    );
+    opts.optflag(
+        "",
+        "all-addresses",
+        "Ring every resolved address of the destination, one after another",
+    );
+    opts.optflag(
+        "",
+        "compare",
+        "Ring every resolved address at once and compare their loss and RTT",
+    );
+    opts.optopt(
+        "",
+        "resolver",
+        "Look the destination up at DNS server <addr[:port]> instead of the system resolver",
+        "<SERVER>",
+    );
+    opts.optopt(
+        "",
+        "reresolve",
+        "Look the destination up again every <n> seconds and follow address changes",
+        "<SECS>",
+    );
+    opts.optopt(
+        "",
+        "nth",
+        "Ring the <n>th resolved address of the destination",
+        "<N>",
+    );
+    opts.optflag(
+        "",
+        "rdns",
+        "Show the names (PTR records) of hosts that answer",
+    );
+    opts.optflag(
+        "",
+        "show-tstamp",
+        "Show where each reply's send and receive timestamps came from",
+    );
+    opts.optflag(
+        "",
+        "embed-time",
+        "Carry the send time in each request, to time late and out-of-order replies",
+    );
     opts.optflag("h", "help", "Print this help message");
     opts.optflag("v", "version", "Print current Ring version");
     opts.optflag("b", "broadcast", "Enable ringing broadcast address");
+    opts.optopt(
+        "I",
+        "interface",
+        "Ring from source address or interface <addr|iface>",
+        "<IFACE>",
+    );
+    opts.optopt(
+        "",
+        "netns",
+        "Ring from inside network namespace <name|path>",
+        "<NAME>",
+    );
+    opts.optopt(
+        "",
+        "vrf",
+        "Ring through the routing table of VRF <device>",
+        "<VRF>",
+    );
+    opts.optflag(
+        "r",
+        "raw",
+        "Use a raw ICMP socket instead of an unprivileged one",
+    );
+    opts.optopt(
+        "",
+        "tcp",
+        "Ring TCP port <port> with handshakes instead of ICMP echo requests",
+        "<PORT>",
+    );
+    opts.optopt(
+        "",
+        "mcast-if",
+        "Send multicast rings out of <iface|addr>",
+        "<IFACE>",
+    );
 
     // A optional, argument option
     opts.optflagopt("c", "count", "Stop ringing after <count> times", "<COUNT>");
     opts.optflagopt("t", "ttl", "Set time-to-live value", "ring -t<n> <dest>");
+    opts.optopt(
+        "Q",
+        "tos",
+        "Mark requests with TOS/traffic class <n> or DSCP <name>",
+        "<TOS>",
+    );
     opts.optflagopt(
         "i",
         "interval",
         "Wait <n> seconds before each echo request",
         "ring -i2 <destination>",
     );
+    opts.optflag(
+        "D",
+        "",
+        "Start every line with the Unix time, like --timestamp=epoch",
+    );
+    opts.optopt(
+        "",
+        "timestamp",
+        "Start every line with the time as <epoch|iso8601|relative>",
+        "<FORMAT>",
+    );
+    opts.optopt(
+        "",
+        "stats-every",
+        "Print the stats so far every <n> seconds",
+        "<SECS>",
+    );
+    opts.optopt(
+        "l",
+        "preload",
+        "Send <n> requests back to back before ringing every interval",
+        "<N>",
+    );
     opts.optflagopt(
         "d",
         "timeout",
@@ -182,19 +630,61 @@ fn main() -> Result<(), RingError> {
         return Ok(());
     }
 
-    let ip = if matches.opt_present("4") {
-        opt = RingOptions::new_ip4().unwrap();
+    // Sockets belong to the namespace they were created in, so switch before opening any
+    if let Some(ns) = matches.opt_str("netns") {
+        if let Err(e) = iputils::enter_netns(&ns) {
+            match e.raw_os_error() {
+                Some(libc::ENOENT) => eprintln!("\n\x1b[1;31mError: No such network namespace: {ns}\x1b[0m"),
+                Some(libc::EPERM) => eprintln!("\n\x1b[1;31mError: Entering network namespace {ns} needs CAP_SYS_ADMIN (run ring as root)\x1b[0m"),
+                _ => eprintln!("\n\x1b[1;31mError: Can't enter network namespace {ns}: {e}\x1b[0m"),
+            }
+            return Err(RingError::IoError(e));
+        }
+    }
+    let raw = matches.opt_present("r");
+    let tcp = match matches.opt_str("tcp").map(|p| p.parse::<u16>()) {
+        Some(Ok(p)) if p > 0 => Some(p),
+        Some(_) => {
+            eprintln!("\n\x1b[1;31mError: --tcp takes a port number (1-65535)\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+        None => None,
+    };
+    // Handshakes carry no payload, and go through sockets of their own
+    if tcp.is_some() && (raw || matches.opt_present("b") || matches.opt_present("embed-time")) {
+        eprintln!("\n\x1b[1;31mError: --tcp can't be combined with -r, -b or --embed-time\x1b[0m");
+        return Err(RingError::ArgError);
+    }
+    // A source address only works with destinations of its own family
+    let source = matches.opt_str("I").and_then(|i| i.parse::<IpAddr>().ok());
+    let ip = if matches.opt_present("4") || source.is_some_and(|s| s.is_ipv4()) {
+        opt = RingOptions::new_ip4(raw, tcp).map_err(report)?;
         Some(IP::V4)
-    } else if matches.opt_present("6") {
-        opt = RingOptions::new().unwrap();
+    } else if matches.opt_present("6") || source.is_some_and(|s| s.is_ipv6()) {
+        opt = RingOptions::new(raw, tcp).map_err(report)?;
         Some(IP::V6)
     } else {
-        opt = RingOptions::new().unwrap();
-        None
+        match RingOptions::new(raw, tcp) {
+            Ok(o) => {
+                opt = o;
+                None
+            }
+            // IPv6 is disabled on this host, so only IPv4 destinations can be rung
+            Err(e) if e.is_family_unsupported() => {
+                opt = RingOptions::new_ip4(raw, tcp).map_err(report)?;
+                Some(IP::V4)
+            }
+            Err(e) => return Err(report(e)),
+        }
     };
     if matches.opt_present("q") {
         opt.set_quite(true);
     }
+    opt.show_tstamp = matches.opt_present("show-tstamp");
+    // -n means no DNS traffic at all, reverse lookups included
+    let numeric = matches.opt_present("n");
+    opt.rdns = matches.opt_present("rdns") && !numeric;
+    opt.embed_time = matches.opt_present("embed-time");
     // TODO: Maybe check and use `unwrap_or_default()`
     if let Some(c) = matches.opt_str("c") {
         opt.set_count(c.parse().unwrap_or(-1));
@@ -204,6 +694,40 @@ fn main() -> Result<(), RingError> {
         opt.interval = i.parse().unwrap_or(1);
     };
 
+    if let Some(l) = matches.opt_str("l") {
+        opt.preload = match l.parse() {
+            Ok(l) if l > 0 => l,
+            _ => {
+                eprintln!("\n\x1b[1;31mError: Invalid preload {l}, use a positive number\x1b[0m");
+                return Err(RingError::ArgError);
+            }
+        };
+    };
+
+    if matches.opt_present("D") {
+        opt.timestamp = Some(iputils::timefmt::Format::Epoch);
+    }
+    if let Some(f) = matches.opt_str("timestamp") {
+        opt.timestamp = match iputils::timefmt::Format::parse(&f) {
+            Some(f) => Some(f),
+            None => {
+                eprintln!("\n\x1b[1;31mError: Invalid timestamp format {f}, use epoch, iso8601 or relative\x1b[0m");
+                return Err(RingError::ArgError);
+            }
+        };
+    }
+    if let Some(n) = matches.opt_str("stats-every") {
+        opt.stats_every = match n.parse() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                eprintln!(
+                    "\n\x1b[1;31mError: --stats-every takes a positive number of seconds\x1b[0m"
+                );
+                return Err(RingError::ArgError);
+            }
+        };
+    }
+
     if let Some(d) = matches.opt_str("d") {
         opt.timeout = d.parse().unwrap_or(1);
         opt.timeout *= 1000; // sec to millisecs
@@ -238,49 +762,194 @@ fn main() -> Result<(), RingError> {
         }
         opt.broadcast = true;
     }
-    let parsed_addr = (opt.addr.as_str(), 0).to_socket_addrs().unwrap();
-    let mut addr;
-    if let Some(i) = ip {
-        if i == IP::V4 {
-            addr = iputils::get_ip4_addr(parsed_addr.to_owned())?;
+    opt.mcast_if = matches.opt_str("mcast-if");
+    opt.interface = matches.opt_str("I");
+    if let Some(iface) = &opt.interface {
+        if iface.parse::<IpAddr>().is_err() {
+            iputils::if_index(iface).map_err(report)?;
+        }
+    }
+    opt.vrf = matches.opt_str("vrf");
+    if let Some(vrf) = &opt.vrf {
+        iputils::if_index(vrf).map_err(report)?;
+        if !iputils::is_vrf(vrf) {
+            eprintln!("\n\x1b[1;31mError: {vrf} is not a VRF device\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+        // Both would bind the socket to a device, and only one binding can win
+        if opt
+            .interface
+            .as_ref()
+            .is_some_and(|i| i.parse::<IpAddr>().is_err())
+        {
+            eprintln!("\n\x1b[1;31mError: --vrf can't be combined with -I <iface>, use -I <addr> to pick a source inside the VRF\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+    }
+    // Scoped IPv6 destinations (fe80::1%eth0) name their interface after the '%'
+    if let Some((_, scope)) = opt.addr.split_once('%') {
+        if scope.parse::<u32>().is_err() {
+            iputils::if_index(scope).map_err(report)?;
+        }
+    }
+    if let Some(t) = matches.opt_str("ttl") {
+        opt.set_ttl(t.parse().unwrap_or(64)).map_err(report)?;
+    }
+    if let Some(q) = matches.opt_str("Q") {
+        let tos = if let Some(tos) = iputils::parse_tos(&q) {
+            tos
         } else {
-            addr = iputils::get_ip6_addr(parsed_addr.to_owned())?;
+            eprintln!("\n\x1b[1;31mError: Invalid TOS {q}, use a number (0-255) or a DSCP name like ef or af41\x1b[0m");
+            return Err(RingError::ArgError);
+        };
+        opt.set_tos(tos).map_err(report)?;
+    }
+    let nth = match matches.opt_str("nth").map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) if n > 0 => Some(n),
+        Some(_) => {
+            eprintln!("\n\x1b[1;31mError: --nth takes a positive number\x1b[0m");
+            return Err(RingError::ArgError);
         }
-        opt.socket.connect(&SockAddr::from(addr))?;
-    } else {
-        addr = match iputils::get_ip6_addr(parsed_addr.to_owned()) {
-            Ok(ip) => ip,
-            Err(_) => {
-                let ip4 = iputils::get_ip4_addr(parsed_addr.to_owned())?;
-                opt.set_ipv(Some(IP::V4))?;
-                // socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))?;
-                ip4
+        None => None,
+    };
+    let all_addresses = matches.opt_present("all-addresses");
+    let compare = matches.opt_present("compare");
+    if [nth.is_some(), all_addresses, compare]
+        .iter()
+        .filter(|o| **o)
+        .count()
+        > 1
+    {
+        eprintln!(
+            "\n\x1b[1;31mError: only one of --nth, --all-addresses and --compare can be used\x1b[0m"
+        );
+        return Err(RingError::ArgError);
+    }
+    let server = match matches.opt_str("resolver") {
+        Some(_) if numeric => {
+            eprintln!("\n\x1b[1;31mError: -n forbids DNS lookups, drop --resolver or -n\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+        Some(r) => match resolver::dns::parse_server(&r) {
+            Some(server) => Some(server),
+            None => {
+                eprintln!("\n\x1b[1;31mError: Invalid resolver {r}, use an address like 10.0.0.53 or [2001:db8::53]:5353\x1b[0m");
+                return Err(RingError::ArgError);
             }
-        };
-        match opt.socket.connect(&SockAddr::from(addr)) {
-            Ok(_) => {}
-            Err(_) => {
-                // if one fails, try everything.
-                if addr.is_ipv6() {
-                    addr = iputils::get_ip4_addr(parsed_addr.clone())?;
-                    opt.socket.connect(&SockAddr::from(addr))?;
-                } else {
-                    addr = iputils::get_ip6_addr(parsed_addr.clone())?;
-                    opt.socket.connect(&SockAddr::from(addr))?;
-                }
+        },
+        None => None,
+    };
+    opt.resolver = server;
+    opt.reresolve = match matches.opt_str("reresolve").map(|r| r.parse::<u64>()) {
+        Some(Ok(r)) if r > 0 => Some(r),
+        Some(_) => {
+            eprintln!("\n\x1b[1;31mError: --reresolve takes a positive number of seconds\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+        None => None,
+    };
+    if opt.reresolve.is_some() {
+        let literal = opt
+            .addr
+            .split_once('%')
+            .map_or(opt.addr.as_str(), |(a, _)| a);
+        if numeric || literal.parse::<IpAddr>().is_ok() {
+            eprintln!("\n\x1b[1;31mError: --reresolve needs a hostname to look up\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+        if nth.is_some() || all_addresses || compare {
+            eprintln!("\n\x1b[1;31mError: --reresolve follows one address, it can't be combined with --nth, --all-addresses or --compare\x1b[0m");
+            return Err(RingError::ArgError);
+        }
+    }
+    let candidates = match resolver::resolve(&opt.addr, ip, numeric, server) {
+        Ok(c) => c,
+        Err(RingError::DestParseError(_)) => {
+            eprintln!(
+                "\n\x1b[1;31mError: {} is not an IP address, and -n forbids DNS lookups\x1b[0m",
+                opt.addr
+            );
+            return Err(RingError::ArgError);
+        }
+        Err(e) => return Err(report(e)),
+    };
+    let mut candidates = match nth {
+        Some(n) if n > candidates.len() => {
+            eprintln!(
+                "\n\x1b[1;31mError: {} has only {} address(es)\x1b[0m",
+                opt.addr,
+                candidates.len()
+            );
+            return Err(RingError::ArgError);
+        }
+        Some(n) => vec![candidates[n - 1]],
+        None => {
+            if candidates.len() > 1 && !all_addresses && !compare {
+                println!("\n\x1b[1;33m[NOTE]: {} has {} addresses, ringing the first reachable one. Pick another with --nth <n>, or use --all-addresses\x1b[0m", opt.addr, candidates.len());
             }
+            candidates
         }
+    };
+    if let Some(port) = opt.tcp {
+        candidates.iter_mut().for_each(|c| c.set_port(port));
     }
-    if let Some(t) = matches.opt_str("ttl") {
-        _ = opt.set_ttl(t.parse().unwrap_or(64));
+    // Installed once, since every ring below shares it
+    let stop = ring_impl::on_interrupt();
+    ring_impl::on_quit();
+
+    if compare {
+        return compare_addresses(&opt, candidates, &url, stop);
     }
-    println!(
-     // Terminal Color(VT100) Specification form (https://chrisyeh96.github.io/2020/03/28/terminal-colors.html)
-     "\n\x1b[1;32mRinging \x1b[0m\x1b[4;34m{}({})\x1b[0m \x1b[1;32mwith \x1b[1;37m{} bytes\x1b[0m\x1b[1;32m of data\x1b[0m\n",
-         url, addr, DATA.len()
-     );
+    if all_addresses {
+        for candidate in candidates {
+            if ring_impl::interrupted(&stop) {
+                break;
+            }
+            let mut opt = opt
+                .with_family(resolver::family_of(&candidate))
+                .map_err(report)?;
+            let mut addr = candidate;
+            if let Some(e) = prepare(&mut opt, &mut addr)? {
+                eprintln!("\n\x1b[1;31mError: Can't ring {addr}: {e}\x1b[0m");
+                continue;
+            }
+            print_banner(&url, addr, opt.tcp);
+            if let Err(e) = ring(opt, addr, stop.clone()) {
+                eprintln!("Error: {e}");
+            }
+        }
+        return Ok(());
+    }
+
+    // Happy Eyeballs without the racing: walk the candidates (already interleaved by family) until
+    // one can be connected to
+    let mut addr = None;
+    let last = candidates.len() - 1;
+    for (i, candidate) in candidates.into_iter().enumerate() {
+        let mut a = candidate;
+        if i > 0 {
+            // The previous attempt may have bound the socket already
+            opt.reopen(resolver::family_of(&a)).map_err(report)?;
+        }
+        match prepare(&mut opt, &mut a)? {
+            None => {
+                addr = Some(a);
+                break;
+            }
+            Some(e) if i == last => {
+                eprintln!("\n\x1b[1;31mError: Can't ring {a}: {e}\x1b[0m");
+                return Err(RingError::IoError(e));
+            }
+            Some(e) => {
+                println!("\n\x1b[1;33m[NOTE]: Can't ring {a}: {e}, trying the next address\x1b[0m");
+            }
+        }
+    }
+    // The loop either found an address or returned
+    let addr = addr.ok_or(RingError::NetworkError)?;
+    print_banner(&url, addr, opt.tcp);
 
-    if let Err(e) = ring_impl::run(opt, addr) {
+    if let Err(e) = ring(opt, addr, stop) {
         eprintln!("Error: {e}");
         return Ok(());
     };
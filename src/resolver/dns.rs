@@ -0,0 +1,503 @@
+// A tiny stub resolver for --resolver: asks one DNS server for the A and AAAA records of a name,
+// bypassing getaddrinfo, /etc/hosts and nsswitch. Queries go over UDP, and are repeated over TCP
+// when the answer comes back truncated or the server doesn't answer UDP at all. See rfc 1035.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::iputils;
+
+pub const DNS_PORT: u16 = 53;
+// Per query and transport, so an unreachable server costs at most twice this per record type
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+// Largest response we take over UDP without EDNS0
+const UDP_LEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => TYPE_A,
+            RecordType::Aaaa => TYPE_AAAA,
+        }
+    }
+}
+
+// Why a lookup failed. Turned into a `RingError::ResolveError` by the caller, which knows the host.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DnsError {
+    // The name doesn't exist (NXDOMAIN)
+    NoSuchName,
+    // Any other response code, like SERVFAIL (2) or REFUSED (5)
+    Server(u8),
+    // The server sent something that isn't an answer to our question
+    Malformed,
+    Timeout,
+    Io(io::ErrorKind),
+    BadName,
+}
+
+impl std::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsError::NoSuchName => write!(f, "no such name"),
+            DnsError::Server(2) => write!(f, "server failure (SERVFAIL)"),
+            DnsError::Server(5) => write!(f, "query refused (REFUSED)"),
+            DnsError::Server(rcode) => write!(f, "server answered with error code {rcode}"),
+            DnsError::Malformed => write!(f, "malformed answer"),
+            DnsError::Timeout => write!(f, "no answer"),
+            DnsError::Io(kind) => write!(f, "{}", io::Error::from(*kind)),
+            DnsError::BadName => write!(f, "not a valid DNS name"),
+        }
+    }
+}
+
+impl From<io::Error> for DnsError {
+    fn from(value: io::Error) -> Self {
+        match value.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DnsError::Timeout,
+            kind => DnsError::Io(kind),
+        }
+    }
+}
+
+// `10.0.0.53`, `10.0.0.53:5353`, `::1` or `[::1]:5353`
+pub fn parse_server(server: &str) -> Option<SocketAddr> {
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, DNS_PORT));
+    }
+    server.parse::<SocketAddr>().ok()
+}
+
+// Every address `server` has for `name` of the asked types, in the order the server listed them.
+// A name with no records of one type is fine as long as the other type has some, and so is a type
+// the server fails to answer for (plenty of them time out or SERVFAIL on AAAA). The lookup only
+// fails when every type does, with the first type's error.
+pub fn lookup(
+    server: SocketAddr,
+    name: &str,
+    types: &[RecordType],
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, DnsError> {
+    let mut addrs = Vec::new();
+    let mut failed = None;
+    let mut answered = false;
+    for &rtype in types {
+        match query(server, name, rtype, timeout) {
+            Ok(found) => {
+                answered = true;
+                addrs.extend(found);
+            }
+            Err(e) => {
+                failed.get_or_insert(e);
+            }
+        }
+    }
+    match (answered, failed) {
+        (false, Some(e)) => Err(e),
+        _ => Ok(addrs),
+    }
+}
+
+fn query(
+    server: SocketAddr,
+    name: &str,
+    rtype: RecordType,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, DnsError> {
+    let id = iputils::run_nonce() as u16;
+    let question = build_query(id, name, rtype)?;
+    match query_udp(server, &question, timeout) {
+        Ok(answer) => match parse_answer(&answer, id, rtype)? {
+            Answer::Records(addrs) => return Ok(addrs),
+            Answer::Truncated => {}
+        },
+        // Some networks drop DNS over UDP, TCP may still get through
+        Err(DnsError::Timeout) => {}
+        Err(e) => return Err(e),
+    }
+    let answer = query_tcp(server, &question, timeout)?;
+    match parse_answer(&answer, id, rtype)? {
+        Answer::Records(addrs) => Ok(addrs),
+        // Nothing bigger than a TCP message exists
+        Answer::Truncated => Err(DnsError::Malformed),
+    }
+}
+
+fn query_udp(server: SocketAddr, question: &[u8], timeout: Duration) -> Result<Vec<u8>, DnsError> {
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local)?;
+    // Only answers from the server itself get through a connected socket
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(question)?;
+    let mut buf = [0u8; UDP_LEN];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        // Stray datagrams (like a late answer to an earlier query) carry another id
+        if len >= 2 && buf[..2] == question[..2] {
+            return Ok(buf[..len].to_vec());
+        }
+    }
+}
+
+// Over TCP every message is preceded by its length (rfc 1035 section 4.2.2)
+fn query_tcp(server: SocketAddr, question: &[u8], timeout: Duration) -> Result<Vec<u8>, DnsError> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut message = (question.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(question);
+    stream.write_all(&message)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut answer = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut answer)?;
+    Ok(answer)
+}
+
+pub fn build_query(id: u16, name: &str, rtype: RecordType) -> Result<Vec<u8>, DnsError> {
+    let mut q = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    q.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired
+    q.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no answer, authority or additional records
+    q.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return Err(DnsError::BadName);
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::BadName);
+        }
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&rtype.code().to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(q)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Answer {
+    Records(Vec<IpAddr>),
+    Truncated,
+}
+
+// Pulls the records of `rtype` out of the answer section. CNAMEs in front of them are skipped, the
+// server already followed them for us.
+fn parse_answer(msg: &[u8], id: u16, rtype: RecordType) -> Result<Answer, DnsError> {
+    if msg.len() < HEADER_LEN || msg[..2] != id.to_be_bytes() {
+        return Err(DnsError::Malformed);
+    }
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    // Has to be a response (QR) to a standard query
+    if flags & 0x8000 == 0 || flags & 0x7800 != 0 {
+        return Err(DnsError::Malformed);
+    }
+    if flags & 0x0200 != 0 {
+        return Ok(Answer::Truncated);
+    }
+    match (flags & 0x000f) as u8 {
+        0 => {}
+        3 => return Err(DnsError::NoSuchName),
+        rcode => return Err(DnsError::Server(rcode)),
+    }
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        // Name, type and class
+        pos = skip_name(msg, pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let fixed = msg.get(pos..pos + 10).ok_or(DnsError::Malformed)?;
+        let rr_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        let data = msg.get(pos..pos + len).ok_or(DnsError::Malformed)?;
+        pos += len;
+        if class != CLASS_IN || rr_type != rtype.code() {
+            continue;
+        }
+        match (rtype, data.len()) {
+            (RecordType::A, 4) => {
+                addrs.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap()));
+            }
+            (RecordType::Aaaa, 16) => {
+                addrs.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap()));
+            }
+            _ => return Err(DnsError::Malformed),
+        }
+    }
+    Ok(Answer::Records(addrs))
+}
+
+// Offset right after the (possibly compressed) name starting at `pos`
+fn skip_name(msg: &[u8], mut pos: usize) -> Result<usize, DnsError> {
+    loop {
+        let len = *msg.get(pos).ok_or(DnsError::Malformed)?;
+        match len {
+            0 => return Ok(pos + 1),
+            // A pointer ends the name, wherever it points to
+            l if l & 0xc0 == 0xc0 => {
+                msg.get(pos + 1).ok_or(DnsError::Malformed)?;
+                return Ok(pos + 2);
+            }
+            l if l & 0xc0 != 0 => return Err(DnsError::Malformed),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const SHORT: Duration = Duration::from_millis(300);
+
+    // An answer to `query` holding `records` of its type. The owner name is a pointer to the
+    // question, like real servers send it.
+    fn respond(query: &[u8], flags: u16, records: &[&[u8]]) -> Vec<u8> {
+        let qend = skip_name(query, HEADER_LEN).unwrap() + 4;
+        let qtype = [query[qend - 4], query[qend - 3]];
+        let mut r = query[..2].to_vec();
+        r.extend_from_slice(&flags.to_be_bytes());
+        r.extend_from_slice(&[0, 1]);
+        r.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        r.extend_from_slice(&[0, 0, 0, 0]);
+        r.extend_from_slice(&query[HEADER_LEN..qend]);
+        for data in records {
+            r.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            r.extend_from_slice(&qtype);
+            r.extend_from_slice(&CLASS_IN.to_be_bytes());
+            r.extend_from_slice(&300u32.to_be_bytes());
+            r.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            r.extend_from_slice(data);
+        }
+        r
+    }
+
+    fn record_type(query: &[u8]) -> u16 {
+        let qend = skip_name(query, HEADER_LEN).unwrap();
+        u16::from_be_bytes([query[qend], query[qend + 1]])
+    }
+
+    // A stub DNS server on a random local port that answers `queries` UDP queries with `answer`
+    fn udp_stub(queries: usize, answer: fn(&[u8]) -> Option<Vec<u8>>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; UDP_LEN];
+            for _ in 0..queries {
+                let (len, from) = socket.recv_from(&mut buf).unwrap();
+                if let Some(a) = answer(&buf[..len]) {
+                    socket.send_to(&a, from).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    // The TCP side of the same stub, listening on the same port as `udp`
+    fn tcp_stub(udp: SocketAddr, answer: fn(&[u8]) -> Vec<u8>) {
+        let listener = TcpListener::bind(udp).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).unwrap();
+            let a = answer(&query);
+            stream.write_all(&(a.len() as u16).to_be_bytes()).unwrap();
+            stream.write_all(&a).unwrap();
+        });
+    }
+
+    #[test]
+    fn builds_queries() {
+        let q = build_query(0x1234, "ring.example.", RecordType::Aaaa).unwrap();
+        assert_eq!(
+            &q[..HEADER_LEN],
+            &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(&q[HEADER_LEN..], b"\x04ring\x07example\x00\x00\x1c\x00\x01");
+        assert_eq!(
+            build_query(1, "a..b", RecordType::A),
+            Err(DnsError::BadName)
+        );
+        assert_eq!(
+            build_query(1, &"a".repeat(64), RecordType::A),
+            Err(DnsError::BadName)
+        );
+    }
+
+    #[test]
+    fn resolves_a_and_aaaa_over_udp() {
+        let server = udp_stub(2, |q| {
+            Some(match record_type(q) {
+                TYPE_A => respond(q, 0x8180, &[&[192, 0, 2, 1], &[192, 0, 2, 2]]),
+                _ => respond(q, 0x8180, &[&Ipv6Addr::LOCALHOST.octets()]),
+            })
+        });
+        let addrs = lookup(
+            server,
+            "ring.test",
+            &[RecordType::Aaaa, RecordType::A],
+            SHORT,
+        );
+        assert_eq!(
+            addrs,
+            Ok(vec![
+                IpAddr::from(Ipv6Addr::LOCALHOST),
+                IpAddr::from([192, 0, 2, 1]),
+                IpAddr::from([192, 0, 2, 2]),
+            ])
+        );
+    }
+
+    #[test]
+    fn one_failed_type_keeps_the_others() {
+        // SERVFAIL on AAAA
+        let server = udp_stub(2, |q| {
+            Some(match record_type(q) {
+                TYPE_A => respond(q, 0x8180, &[&[192, 0, 2, 3]]),
+                _ => respond(q, 0x8182, &[]),
+            })
+        });
+        let types = [RecordType::Aaaa, RecordType::A];
+        let addrs = lookup(server, "v4only.test", &types, SHORT);
+        assert_eq!(addrs, Ok(vec![IpAddr::from([192, 0, 2, 3])]));
+        // No answer at all to AAAA, over UDP or TCP
+        let server = udp_stub(2, |q| match record_type(q) {
+            TYPE_A => Some(respond(q, 0x8180, &[&[192, 0, 2, 4]])),
+            _ => None,
+        });
+        let addrs = lookup(server, "v4only.test", &types, SHORT);
+        assert_eq!(addrs, Ok(vec![IpAddr::from([192, 0, 2, 4])]));
+        // Every type failing still fails, with the first error
+        let server = udp_stub(2, |q| Some(respond(q, 0x8182, &[])));
+        let addrs = lookup(server, "down.test", &types, SHORT);
+        assert_eq!(addrs, Err(DnsError::Server(2)));
+    }
+
+    #[test]
+    fn falls_back_to_tcp_when_truncated() {
+        let server = udp_stub(1, |q| Some(respond(q, 0x8380, &[])));
+        tcp_stub(server, |q| respond(q, 0x8180, &[&[198, 51, 100, 7]]));
+        let addrs = lookup(server, "big.test", &[RecordType::A], SHORT);
+        assert_eq!(addrs, Ok(vec![IpAddr::from([198, 51, 100, 7])]));
+    }
+
+    #[test]
+    fn falls_back_to_tcp_when_udp_is_silent() {
+        let server = udp_stub(1, |_| None);
+        tcp_stub(server, |q| respond(q, 0x8180, &[&[198, 51, 100, 8]]));
+        let addrs = lookup(server, "tcp.test", &[RecordType::A], SHORT);
+        assert_eq!(addrs, Ok(vec![IpAddr::from([198, 51, 100, 8])]));
+    }
+
+    #[test]
+    fn times_out_without_a_server() {
+        // Nobody answers UDP, and nothing listens on TCP either
+        let server = udp_stub(1, |_| None);
+        let err = lookup(server, "gone.test", &[RecordType::A], SHORT).unwrap_err();
+        assert!(
+            matches!(err, DnsError::Timeout | DnsError::Io(_)),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn reports_response_codes() {
+        let server = udp_stub(1, |q| Some(respond(q, 0x8183, &[])));
+        assert_eq!(
+            lookup(server, "nx.test", &[RecordType::A], SHORT),
+            Err(DnsError::NoSuchName)
+        );
+        let server = udp_stub(1, |q| Some(respond(q, 0x8182, &[])));
+        assert_eq!(
+            lookup(server, "fail.test", &[RecordType::A], SHORT),
+            Err(DnsError::Server(2))
+        );
+    }
+
+    #[test]
+    fn skips_cnames_and_other_types() {
+        let q = build_query(7, "www.test", RecordType::A).unwrap();
+        let mut r = respond(&q, 0x8180, &[]);
+        r[7] = 2;
+        // www.test CNAME web.test, then web.test A 203.0.113.5
+        r.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        let web = r.len();
+        r.extend_from_slice(b"\x03web\xc0\x10");
+        r.extend_from_slice(&[
+            0xc0, web as u8, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 5,
+        ]);
+        assert_eq!(
+            parse_answer(&r, 7, RecordType::A),
+            Ok(Answer::Records(vec![IpAddr::from([203, 0, 113, 5])]))
+        );
+    }
+
+    #[test]
+    fn rejects_short_and_foreign_answers() {
+        let q = build_query(9, "short.test", RecordType::A).unwrap();
+        let r = respond(&q, 0x8180, &[&[192, 0, 2, 9]]);
+        for len in 0..r.len() {
+            assert!(parse_answer(&r[..len], 9, RecordType::A).is_err(), "{len}");
+        }
+        // Wrong id, and a query instead of a response
+        assert_eq!(
+            parse_answer(&r, 10, RecordType::A),
+            Err(DnsError::Malformed)
+        );
+        assert_eq!(parse_answer(&q, 9, RecordType::A), Err(DnsError::Malformed));
+        // An A record that isn't 4 bytes long
+        let bad = respond(&q, 0x8180, &[&[192, 0, 2]]);
+        assert_eq!(
+            parse_answer(&bad, 9, RecordType::A),
+            Err(DnsError::Malformed)
+        );
+    }
+
+    #[test]
+    fn parses_server_addresses() {
+        assert_eq!(
+            parse_server("10.0.0.53"),
+            Some("10.0.0.53:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_server("10.0.0.53:5353"),
+            Some("10.0.0.53:5353".parse().unwrap())
+        );
+        assert_eq!(parse_server("::1"), Some("[::1]:53".parse().unwrap()));
+        assert_eq!(
+            parse_server("[::1]:5353"),
+            Some("[::1]:5353".parse().unwrap())
+        );
+        assert_eq!(parse_server("dns.example"), None);
+    }
+}
@@ -0,0 +1,150 @@
+// Turns the destination the user typed into the addresses ring may use, in the order it tries them.
+//
+// Preference order:
+// 1. -4 / -6 (or an -I source address) drop every address of the other family.
+// 2. The system resolver (getaddrinfo) sorts the rest by rfc 6724, which /etc/gai.conf can tune.
+//    On a host with working IPv6 that usually puts AAAA records first.
+// 3. Like Happy Eyeballs (rfc 8305 section 4), the two families are then interleaved, starting with
+//    the family of the first address. If the first address turns out to be unusable (no route,
+//    family disabled), the next candidate is of the other family instead of more of the same.
+//
+// `--nth <n>` picks the n-th address of that list, and `--all-addresses` rings all of them.
+//
+// With `--resolver <server>` the names are looked up by asking that server directly (see `dns`),
+// and the order is whatever the server answered, AAAA records first.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::RingError;
+use crate::iputils;
+use crate::IP;
+
+pub mod dns;
+
+use dns::RecordType;
+
+pub fn resolve(
+    host: &str,
+    family: Option<IP>,
+    numeric: bool,
+    server: Option<SocketAddr>,
+) -> Result<Vec<SocketAddr>, RingError> {
+    // Addresses don't need a DNS server, even when one was given
+    let literal = host
+        .split_once('%')
+        .map_or(host, |(ip, _)| ip)
+        .parse::<IpAddr>()
+        .is_ok();
+    let found = if numeric || literal {
+        numeric_addr(host)?
+    } else if let Some(server) = server {
+        let types = match family {
+            Some(IP::V4) => &[RecordType::A][..],
+            Some(IP::V6) => &[RecordType::Aaaa][..],
+            None => &[RecordType::Aaaa, RecordType::A][..],
+        };
+        match dns::lookup(server, host, types, dns::QUERY_TIMEOUT) {
+            Ok(addrs) => addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect(),
+            Err(e) => {
+                return Err(RingError::ResolveError {
+                    host: host.to_owned(),
+                    reason: format!("{e} (asked {server})"),
+                })
+            }
+        }
+    } else {
+        match (host, 0).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                return Err(RingError::ResolveError {
+                    host: host.to_owned(),
+                    reason: e.to_string(),
+                })
+            }
+        }
+    };
+    let mut candidates: Vec<SocketAddr> = Vec::new();
+    for addr in found {
+        if family.is_none_or(|f| f == family_of(&addr)) && !candidates.contains(&addr) {
+            candidates.push(addr);
+        }
+    }
+    if candidates.is_empty() {
+        let reason = match family {
+            Some(IP::V4) => "no IPv4 address",
+            Some(IP::V6) => "no IPv6 address",
+            None => "no address",
+        };
+        return Err(RingError::ResolveError {
+            host: host.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+    Ok(interleave(candidates))
+}
+
+// --reresolve: look `host` up again every `every` in the background, so a slow DNS server never
+// holds up the rings. Stops after the receiving end is dropped.
+pub fn watch(
+    host: String,
+    family: IP,
+    server: Option<SocketAddr>,
+    every: Duration,
+) -> Receiver<Result<Vec<SocketAddr>, RingError>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || loop {
+        thread::sleep(every);
+        if tx
+            .send(resolve(&host, Some(family), false, server))
+            .is_err()
+        {
+            break;
+        }
+    });
+    rx
+}
+
+pub fn family_of(addr: &SocketAddr) -> IP {
+    if addr.is_ipv4() {
+        IP::V4
+    } else {
+        IP::V6
+    }
+}
+
+// Alternate address families, keeping the resolver's order within each family
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first = family_of(&addrs[0]);
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| family_of(a) == first);
+    preferred.reverse();
+    other.reverse();
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+// -n: take the destination as a literal address and never touch DNS. IPv6 literals may carry a
+// scope after '%', either an interface name or its index.
+fn numeric_addr(host: &str) -> Result<Vec<SocketAddr>, RingError> {
+    let (ip, scope) = match host.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (host, None),
+    };
+    let mut addr = SocketAddr::new(ip.parse::<IpAddr>()?, 0);
+    if let (SocketAddr::V6(a), Some(scope)) = (&mut addr, scope) {
+        a.set_scope_id(match scope.parse::<u32>() {
+            Ok(i) => i,
+            Err(_) => iputils::if_index(scope)?,
+        });
+    }
+    Ok(vec![addr])
+}
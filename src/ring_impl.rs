@@ -1,306 +1,870 @@
-use crate::iputils::EchoICMP;
+use crate::error::RingError;
+use crate::iputils::{
+    rdns::Names,
+    timefmt,
+    tstamp::{self, Source, Stamp},
+};
+use crate::resolver;
+use crate::transport::{Answer, Kind, PortState, ProbeTransport, Reply};
 use crate::RingOptions;
-use crate::{error::RingError, DATA_LENGTH};
 
-use socket2::Socket;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::{
-    io::Read,
     sync::{
-        atomic::AtomicBool,
+        atomic::{AtomicUsize, Ordering},
         mpsc::{self, channel},
         Arc, Condvar, Mutex,
     },
     thread, time,
 };
 
-#[derive(Debug, PartialEq, Eq)]
-struct RingStats {
-    packet_sent: u32,
-    successful: u32,
-    loss: u32,
-    // Better than using `Duration` as `Instant` exists specially for this purpose
-    time: time::Instant,
+// An echo request that went out, with the clocks needed to time its reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Probe {
+    // Counts every request of the ring. Only its low 16 bits go on the wire, so those wrap around
+    // on long rings while this keeps going.
+    seq: u64,
+    // For timeouts. Monotonic, so wall clock jumps can't break them
+    sent: time::Instant,
+    // For the RTT. Kernel receive stamps are wall clock, so the send time has to be too
+    sent_at: Stamp,
 }
 
-impl Default for RingStats {
-    fn default() -> Self {
-        Self {
-            packet_sent: 0,
-            successful: 0,
-            loss: 0,
-            time: time::Instant::now(),
-        }
-    }
+// Set once Ctrl + C is pressed. Every ring waits on it between requests, so a single handler stops
+// all of them.
+pub type Interrupt = Arc<(Mutex<bool>, Condvar)>;
+
+// ctrlc only takes one handler per process, so this has to run once, before the first ring
+pub fn on_interrupt() -> Interrupt {
+    let pcond: Interrupt = Arc::new((Mutex::new(false), Condvar::new()));
+    let scond = pcond.clone();
+    ctrlc::set_handler(move || {
+        let (lock, cond) = &*scond;
+        // Unwrap seems good here. There is not much we can do if the lock is poisoned.
+        // The best thing to do would be to exit the program.
+        let mut lock = lock.lock().expect("Failed to aquire the lock");
+        *lock = true;
+        cond.notify_all();
+    })
+    .expect("Failed to register callback");
+    pcond
+}
+
+pub fn interrupted(stop: &Interrupt) -> bool {
+    *stop.0.lock().unwrap()
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq)]
 enum RingMessage {
-    Continue((u16, time::Instant)),
+    Continue(Probe),
+    // That was the last request, finish the ones still out
     Stop,
+    // Ctrl + C, stop right away
+    Abort,
 }
 
-// Accepts a data buffer checks if the checksum is correct.
-// If not, some data has been corrupted
-// Making global as it can't be tied down to any struct
-fn check_checksum(bytes: &mut [u8]) -> bool {
-    // let mut final_checksum = 0;
-    let mut chck = 0_u32;
-    for word in bytes.chunks(2) {
-        let mut part = u16::from(word[0]) << 8;
-        if word.len() > 1 {
-            part += u16::from(word[1]);
+// What became of the requests of a ring. Every transmitted request ends up as exactly one of
+// received, errors, timed_out or in_flight; duplicates, corrupted and late count extra replies.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RingStats {
+    pub transmitted: u32,
+    // Requests with at least one good reply
+    pub received: u32,
+    // Requests answered by an ICMP error (unreachable, TTL exceeded, ...)
+    pub errors: u32,
+    // Requests that got nothing before their deadline
+    pub timed_out: u32,
+    // Requests still waiting for their deadline when the ring ended
+    pub in_flight: u32,
+    // Replies to requests that were answered already
+    pub duplicates: u32,
+    // Replies with a bad checksum. Their request keeps waiting for a good one.
+    pub corrupted: u32,
+    // Replies that came after their request timed out
+    pub late: u32,
+}
+
+impl RingStats {
+    // Requests whose fate is known
+    fn settled(&self) -> u32 {
+        self.transmitted - self.in_flight
+    }
+    fn lost(&self) -> u32 {
+        self.errors + self.timed_out
+    }
+    // Requests still in flight are neither lost nor received, so they are left out
+    fn loss(&self) -> u32 {
+        match self.settled() {
+            0 => 0,
+            settled => self.lost() * 100 / settled,
         }
-        chck = chck.wrapping_add(u32::from(part));
     }
+}
 
-    while (chck >> 16) > 0 {
-        chck = (chck & 0xffff) + (chck >> 16);
-    }
+// What a reply turned out to be
+#[derive(Debug, PartialEq, Eq)]
+enum Verdict {
+    // A good reply to an outstanding request. `first` is false for the other responders of a
+    // one-to-many ring.
+    Answer { probe: Probe, first: bool },
+    Duplicate,
+    Corrupted,
+    Late,
+}
 
-    let chck = !chck as u16;
-    chck == 0
+// The requests of a ring from being handed over by the sender until they are answered, fail or
+// time out. Keeps the books in `stats` as it goes.
+#[derive(Default)]
+struct Ledger {
+    // Requests that are out and still before their deadline, oldest first
+    probes: Vec<Probe>,
+    // Newest sequence handed over so far
+    last: Option<u64>,
+    // Sequences with a good reply
+    answered: HashSet<u64>,
+    // One-to-many rings: who answered which sequence, and the sequences an ICMP error came back for
+    seen: HashSet<(u64, Option<IpAddr>)>,
+    failed: HashSet<u64>,
+    one_to_many: bool,
+    // No more requests are coming (the count ran out)
+    finishing: bool,
+    // Ctrl + C
+    aborted: bool,
+    stats: RingStats,
 }
 
-// parse ICMP error messages. See rfc 792
-fn parse_error(mtype: u8, code: u8, seq: u16) {
-    match mtype {
-        3 => match code {
-            0 => println!(
-                "\x1b[1;31mDestination Network Unreachable. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            1 => println!(
-                "\x1b[1;31mDestination Host Unreachable. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            2 => println!(
-                "\x1b[1;31mDestination Protocol Unreachable. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            3 => println!(
-                "\x1b[1;31mDestination Port Unreachable. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            4 => println!(
-                "\x1b[1;31mFragmentation Needed. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            5 => println!(
-                "\x1b[1;31mSource Route Failed. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            _ => (),
-        },
-        4 => {
-            if code == 0 {
-                println!(
-                    "\x1b[1;31mSource Quench. ICMP Sequence Packet: {}\x1b[0m",
-                    seq
-                )
+impl Ledger {
+    fn new(one_to_many: bool) -> Self {
+        Self {
+            one_to_many,
+            ..Default::default()
+        }
+    }
+    fn sent(&mut self, probe: Probe) {
+        self.stats.transmitted += 1;
+        self.last = Some(probe.seq);
+        // Replies to requests a whole wire sequence space ago can't be told apart from new ones
+        // anyway, so there's no point remembering them on long rings
+        if probe.seq.is_multiple_of(SEQ_SPACE) {
+            let keep = |seq: u64| probe.seq - seq < SEQ_SPACE;
+            self.answered.retain(|&seq| keep(seq));
+            self.seen.retain(|&(seq, _)| keep(seq));
+            self.failed.retain(|&seq| keep(seq));
+        }
+        self.probes.push(probe);
+    }
+    fn take(&mut self, m: RingMessage) {
+        match m {
+            RingMessage::Continue(p) => self.sent(p),
+            RingMessage::Stop => self.finishing = true,
+            RingMessage::Abort => self.aborted = true,
+        }
+    }
+    // Take every message the sender has queued up
+    fn drain(&mut self, rx: &mpsc::Receiver<RingMessage>) {
+        loop {
+            match rx.try_recv() {
+                Ok(m) => self.take(m),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.finishing = true;
+                    break;
+                }
             }
         }
-        11 => match code {
-            0 => println!(
-                "\x1b[1;31mTime to Live Exceeded. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            1 => println!(
-                "\x1b[1;31mFragementation limit Exceeded. ICMP Sequence Packet: {}\x1b[0m",
-                seq
-            ),
-            _ => (),
-        },
-        12 => {
-            if code == 0 {
-                println!(
-                    "\x1b[1;31mParameter Problem. ICMP Sequence Packet: {}\x1b[0m",
-                    seq
-                )
+    }
+    // A reply for a sequence newer than any request we know of is for one still being handed over
+    fn wait_for(&mut self, rx: &mpsc::Receiver<RingMessage>, seq: u16) {
+        let start = time::Instant::now();
+        while self
+            .last
+            .is_none_or(|last| (seq.wrapping_sub(last as u16) as i16) > 0)
+        {
+            match rx.recv_timeout(HANDOVER_WAIT.saturating_sub(start.elapsed())) {
+                Ok(m) => self.take(m),
+                Err(_) => break,
+            }
+        }
+    }
+    // The request a wire sequence belongs to: the newest one sent with those low 16 bits
+    fn unwrap(&self, seq: u16) -> u64 {
+        match self.last {
+            Some(last) => last.saturating_sub(u64::from((last as u16).wrapping_sub(seq))),
+            None => u64::from(seq),
+        }
+    }
+    fn position(&self, seq: u64) -> Option<usize> {
+        self.probes.iter().position(|p| p.seq == seq)
+    }
+    // Requests still waiting for a reply. Answered one-to-many requests are only collecting more
+    // responders, they don't count.
+    fn in_flight(&self) -> usize {
+        self.probes
+            .iter()
+            .filter(|p| !self.answered.contains(&p.seq))
+            .count()
+    }
+    fn reply(&mut self, seq: u64, from: Option<IpAddr>, intact: bool) -> Verdict {
+        if !intact {
+            self.stats.corrupted += 1;
+            return Verdict::Corrupted;
+        }
+        let at = match self.position(seq) {
+            Some(at) => at,
+            None if self.answered.contains(&seq) => {
+                self.stats.duplicates += 1;
+                return Verdict::Duplicate;
+            }
+            None => {
+                self.stats.late += 1;
+                return Verdict::Late;
+            }
+        };
+        if self.one_to_many {
+            // Every host may answer once
+            if !self.seen.insert((seq, from)) {
+                self.stats.duplicates += 1;
+                return Verdict::Duplicate;
             }
+            let first = self.answered.insert(seq);
+            if first {
+                self.stats.received += 1;
+            }
+            return Verdict::Answer {
+                probe: self.probes[at].clone(),
+                first,
+            };
+        }
+        self.answered.insert(seq);
+        self.stats.received += 1;
+        Verdict::Answer {
+            probe: self.probes.remove(at),
+            first: true,
+        }
+    }
+    // An ICMP error quoting request `seq`. False when that isn't one of ours that is still out.
+    fn error(&mut self, seq: u64) -> bool {
+        let at = match self.position(seq) {
+            Some(at) => at,
+            None => return false,
+        };
+        if self.one_to_many {
+            // Another host may still answer, so it's settled at the deadline
+            self.failed.insert(seq);
+        } else {
+            self.probes.remove(at);
+            self.stats.errors += 1;
+        }
+        true
+    }
+    // A TX stamp that came after request `seq` was handed over. Only any use while it's out.
+    fn restamp(&mut self, seq: u64, stamp: Stamp) {
+        if let Some(at) = self.position(seq) {
+            let probe = &mut self.probes[at];
+            probe.sent_at = probe.sent_at.merge(stamp);
+        }
+    }
+    // Settles a request that leaves without a reply of its own. True when it timed out.
+    fn settle(&mut self, probe: &Probe, expired: bool) -> bool {
+        if self.answered.contains(&probe.seq) {
+            false
+        } else if self.failed.contains(&probe.seq) {
+            self.stats.errors += 1;
+            false
+        } else if expired {
+            self.stats.timed_out += 1;
+            true
+        } else {
+            self.stats.in_flight += 1;
+            false
         }
-        _ => (),
+    }
+    // Drops the requests whose deadline passed by `now`, returning the ones that timed out
+    fn expire(&mut self, now: time::Instant, timeout: time::Duration) -> Vec<u64> {
+        let (expired, waiting): (Vec<Probe>, Vec<Probe>) = std::mem::take(&mut self.probes)
+            .into_iter()
+            .partition(|p| now.duration_since(p.sent) >= timeout);
+        self.probes = waiting;
+        expired
+            .into_iter()
+            .filter(|p| self.settle(p, true))
+            .map(|p| p.seq)
+            .collect()
+    }
+    // The books so far, as if the ring ended now
+    fn snapshot(&self) -> RingStats {
+        let mut stats = self.stats.clone();
+        for p in self
+            .probes
+            .iter()
+            .filter(|p| !self.answered.contains(&p.seq))
+        {
+            match self.failed.contains(&p.seq) {
+                true => stats.errors += 1,
+                false => stats.in_flight += 1,
+            }
+        }
+        stats
+    }
+    fn finish(mut self) -> RingStats {
+        for p in std::mem::take(&mut self.probes) {
+            self.settle(&p, false);
+        }
+        self.stats
     }
 }
 
+// Prefer stamps the kernel (or NIC) took on both ends, as they leave out our own syscall and
+// scheduling latency. Falls back to the monotonic clock when the receive side has no stamp, or the
+// wall clock jumped in between.
+fn rtt(probe: &Probe, received: Option<Stamp>) -> (time::Duration, Source, Source) {
+    received
+        .and_then(|rx| tstamp::between(&probe.sent_at, &rx))
+        .unwrap_or((probe.sent.elapsed(), Source::User, Source::User))
+}
+
+// Time a reply from the send time it carried back in its payload (--embed-time). Works for replies
+// that arrive late or out of order, when `probe` is no longer the request they answer.
+fn payload_rtt(
+    sent: time::SystemTime,
+    received: Option<Stamp>,
+) -> (time::Duration, Source, Source) {
+    // The payload was stamped with the system clock, so the NIC's stamp is no use here
+    let rx = received.unwrap_or(Stamp::user(time::SystemTime::now()));
+    (
+        rx.time.duration_since(sent).unwrap_or_default(),
+        Source::Payload,
+        rx.source,
+    )
+}
+
+// TTL and IP options of a reply (only known when the IPv4 header came along with it), and the TOS
+// it arrived with. When the requests were marked with -Q, flag replies whose DSCP got rewritten
+// somewhere along the path.
+fn reply_info(reply: &Reply, sent_tos: Option<u8>) -> String {
+    let mut info = String::new();
+    if let Some(ttl) = reply.ttl {
+        info.push_str(&format!(", \x1b[1;32mTTL: \x1b[1;37m{}", ttl));
+    }
+    match (reply.tos, sent_tos) {
+        // The low two bits are ECN, which routers may legitimately set
+        (Some(tos), Some(sent)) if tos >> 2 != sent >> 2 => info.push_str(&format!(
+            ", \x1b[1;32mTOS: \x1b[1;33m{:#04x} (remarked from {:#04x})",
+            tos, sent
+        )),
+        (Some(tos), Some(_)) => info.push_str(&format!(", \x1b[1;32mTOS: \x1b[1;37m{:#04x}", tos)),
+        (Some(tos), None) if tos != 0 => {
+            info.push_str(&format!(", \x1b[1;32mTOS: \x1b[1;37m{:#04x}", tos))
+        }
+        _ => {}
+    }
+    if reply.options > 0 {
+        info.push_str(&format!(
+            ", \x1b[1;32mIP Options: \x1b[1;37m{} bytes",
+            reply.options
+        ));
+    }
+    info
+}
+
+// What came back and what it said, as in "21 bytes returned" or "Port 443 open"
+fn returned(answer: Answer) -> (String, &'static str) {
+    match answer {
+        Answer::Echo(len) => (format!("{} bytes", len), "returned"),
+        Answer::Port(port, PortState::Open) => (format!("Port {}", port), "open"),
+        Answer::Port(port, PortState::Refused) => (format!("Port {}", port), "refused"),
+    }
+}
+
+// What the receiver thread saw, handed back to `run` for the summary
+#[derive(Default)]
+struct Returned {
+    stats: RingStats,
+    // Every host that answered a one-to-many ring, with the number of replies it sent
+    responders: HashMap<IpAddr, u32>,
+    names: Names,
+    // Round trip times of the successful requests, in ms
+    rtts: Vec<f64>,
+}
+
+// How a ring to one address went, for comparing the addresses of a host (--compare)
+pub struct Summary {
+    pub dest: SocketAddr,
+    pub stats: RingStats,
+    pub rtts: Vec<f64>,
+}
+
+impl Summary {
+    fn rtt_stats(&self) -> Option<(f64, f64, f64, f64)> {
+        rtt_stats(&self.rtts)
+    }
+}
+
+// (min, avg, max, mdev) like iputils
+fn rtt_stats(rtts: &[f64]) -> Option<(f64, f64, f64, f64)> {
+    if rtts.is_empty() {
+        return None;
+    }
+    let n = rtts.len() as f64;
+    let min = rtts.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = rtts.iter().copied().fold(0.0, f64::max);
+    let avg = rtts.iter().sum::<f64>() / n;
+    let mdev = (rtts.iter().map(|t| t * t).sum::<f64>() / n - avg * avg)
+        .max(0.0)
+        .sqrt();
+    Some((min, avg, max, mdev))
+}
+
+// Bumped on every Ctrl + \ (SIGQUIT). Each ring keeps the last value it saw, so parallel rings
+// (--compare) all notice.
+static QUITS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn count_quit(_: libc::c_int) {
+    QUITS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Like iputils, Ctrl + \ prints the stats so far and keeps ringing. The ctrlc crate only covers
+// SIGINT and SIGTERM, so this one is installed by hand.
+pub fn on_quit() {
+    unsafe {
+        libc::signal(
+            libc::SIGQUIT,
+            count_quit as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+// The stats so far, without stopping (Ctrl + \\ or --stats-every). Requests still waiting for their
+// deadline are left out of the loss.
+fn print_interim(ts: &str, dest: SocketAddr, stats: &RingStats, rtts: &[f64]) {
+    let rtt = match rtt_stats(rtts) {
+        Some((min, avg, max, mdev)) => format!(
+            ", RTT min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+            min, avg, max, mdev
+        ),
+        None => String::new(),
+    };
+    println!(
+        "{}\x1b[1;36m[STATS]: {}: {}/{} packets, {} in flight, {}% loss{}\x1b[0m",
+        ts,
+        dest.ip(),
+        stats.received,
+        stats.settled(),
+        stats.in_flight,
+        stats.loss(),
+        rtt
+    );
+}
+
+// One row per address, so a node behind round-robin DNS that drops or lags stands out. Rows with
+// loss are red, and the slowest average is yellow.
+pub fn print_comparison(summaries: &[Summary]) {
+    let slowest = summaries
+        .iter()
+        .filter_map(|s| s.rtt_stats().map(|(_, avg, _, _)| avg))
+        .fold(None, |m: Option<f64>, avg| {
+            Some(m.map_or(avg, |m| m.max(avg)))
+        });
+    println!("\n\x1b[1;32m------------Ring Comparison------------\x1b[0m\n");
+    println!(
+        "\x1b[1;37m{:<40} {:>6} {:>6} {:>6} {:>9} {:>9} {:>9} {:>9}\x1b[0m",
+        "Address", "Sent", "Recv", "Loss", "Min", "Avg", "Max", "Mdev"
+    );
+    for s in summaries {
+        let rtt = match s.rtt_stats() {
+            Some((min, avg, max, mdev)) => {
+                format!("{:>9.3} {:>9.3} {:>9.3} {:>9.3}", min, avg, max, mdev)
+            }
+            None => format!("{:>9} {:>9} {:>9} {:>9}", "-", "-", "-", "-"),
+        };
+        let color = if s.stats.loss() > 0 {
+            "\x1b[1;31m"
+        } else if summaries.len() > 1 && s.rtt_stats().map(|(_, avg, _, _)| avg) == slowest {
+            "\x1b[1;33m"
+        } else {
+            "\x1b[0m"
+        };
+        println!(
+            "{}{:<40} {:>6} {:>6} {:>5}% {}\x1b[0m",
+            color,
+            s.dest.ip(),
+            s.stats.transmitted,
+            s.stats.received,
+            s.stats.loss(),
+            rtt
+        );
+    }
+    println!("\n(RTTs in ms)");
+}
+
+// Wire sequences are 16 bits
+const SEQ_SPACE: u64 = 1 << 16;
+// How long the receiver sleeps on an idle socket before checking for new requests, deadlines and
+// Ctrl + C again
+const POLL_STEP: time::Duration = time::Duration::from_millis(10);
+// The sender hands a request over only after reading its TX stamp, so on loopback the reply can
+// beat it to the receiver. Wait this long for the request to show up.
+const HANDOVER_WAIT: time::Duration = time::Duration::from_millis(50);
+// After Ctrl + C, wait at most this long (or the timeout, if shorter) for requests still out
+const GRACE: time::Duration = time::Duration::from_secs(1);
+
+// Reads replies for every outstanding request at once. Each request has its own deadline, so a
+// timeout longer than the interval doesn't hold up the requests after it.
 fn handle_returned(
     rx: mpsc::Receiver<RingMessage>,
-    mut recv_socket: Socket,
+    transport: Arc<dyn ProbeTransport>,
     opts: &RingOptions,
-) -> (u32, u32, u32) {
-    let mut rtx = (0u32, 0u32, 0u32);
-    let mut buf = [0; 64];
-    'outer: loop {
-        match rx.recv() {
-            // Don't need to check because there are only two variants and one is already coverd
-            Ok(m) => {
-                let instant;
-                if let RingMessage::Continue((_, i)) = m {
-                    instant = i;
-                    // Can't help spining
-                    // while there is no data on the buffer, and 1s has not elapsed,
-                    // keep spinning. Also keep checking if we receive SIGINT.
-                    while i.elapsed().as_millis() < opts.timeout
-                        && recv_socket.peek_sender().is_err()
-                    {
-                        // If the user presses CTRL + C while we're waiting for a reply, exit every thing
-                        if rx.try_recv().is_ok_and(|v| v == RingMessage::Stop) {
-                            break 'outer;
-                        }
-                        continue;
-                    }
-                } else {
-                    // If there is a packet and that packet is ICMP echo reply, discard it
-                    if recv_socket.peek_sender().is_ok() {
-                        rtx.2 += 1;
-                    }
-                    break;
-                }
-                // Weird hack to return as soon as CTRL + C is hit.
-                // We could do it with timeout, but if we do, pressing CTRL + C
-                // doesn't immediety return
-                match recv_socket.read(&mut buf) {
-                    Ok(i) => {
-                        let time = instant.elapsed().as_millis();
-                        // If Ctrl + C is already pressed, but there is still data on the buffer,
-                        // we currently discard it.
-
-                        // Extracting ip header length.
-                        // When using raw sockets, we get ip header + icmp packet.
-                        // the first octet of the entire packet(ip header + icmp packet) is divided into two sections.
-                        // The first 4 bits of the first octet is the ip version(in ipv4's case 0100), and the low 4 bits
-                        // is the length of the ip header. here we grab the low 4 bits from the first octet(by masking with 0x0f)
-                        // and then multiply by 4 (<< 2) to convert bytes to bits
-                        // only required when using icmpv4 raw packets. isn't needed in dgram and icmpv6 packets.
-                        // let len = ((buf[0] & 0x0F) << 2) as usize; // wtf?
-                        // If the packet isn't ICMP echo reply, discard it.
-                        if !(buf[0] == 129 || buf[0] == 0) {
-                            // parse_error(buf[0], buf[1]); // (code, type)
-                            if let RingMessage::Continue((seq, _)) = m {
-                                parse_error(buf[0], buf[1], seq);
-                            }
-                            rtx.2 += 1;
-                            continue;
-                        }
-
-                        let seq = (buf[6] as u16) << 8 | (buf[7] as u16);
-                        if buf[0] == 0 && !check_checksum(&mut buf[..i]) {
-                            rtx.1 += 1;
-                        } else {
-                            if !opts.quite {
-                                // TODO: fix ttl
-                                println!(
-                        "\x1b[1;32m{} bytes \x1b[37mreturned. \x1b[1;32mICMP Sequence Packet:\x1b[1;37m {}, \x1b[1;32mTime: \x1b[1;37m{} ms\x1b[0m", i-8, seq, time
-                            );
-                            }
-                            rtx.0 += 1;
-                        }
-                    }
-                    Err(_e) => {
-                        let _seq_num;
-                        if let RingMessage::Continue((i, _)) = m {
-                            _seq_num = i;
-                            // We actually report timed-out packets instead of just ignoring it.
-                            // Also destination host unrechable is just timed-out packets.
-                            println!(
-                                "\x1b[1;31mPacket Timed Out. ICMP Sequence Packet: {}\x1b[0m",
-                                _seq_num
-                            );
-                        }
-                    }
+    dest: SocketAddr,
+    start: time::Instant,
+) -> Returned {
+    let mut rtts = Vec::new();
+    let mut responders: HashMap<IpAddr, u32> = HashMap::new();
+    let mut names = Names::new(opts.rdns);
+    let one_to_many = opts.broadcast || opts.multicast;
+    let mut ledger = Ledger::new(one_to_many);
+    let timeout = time::Duration::from_millis(opts.timeout as u64);
+    // Set at Ctrl + C, the last moment we wait for replies to requests still out
+    let mut grace: Option<time::Instant> = None;
+    let mut quits = QUITS.load(Ordering::Relaxed);
+    let mut last_stats = time::Instant::now();
+    let proto = transport.protocol();
+    loop {
+        ledger.drain(&rx);
+        // If the user presses CTRL + C, give the requests still out a moment to come back
+        if ledger.aborted {
+            let until = *grace.get_or_insert_with(|| time::Instant::now() + timeout.min(GRACE));
+            if ledger.in_flight() == 0 || time::Instant::now() >= until {
+                break;
+            }
+        }
+        let quit = QUITS.load(Ordering::Relaxed);
+        let due = opts
+            .stats_every
+            .is_some_and(|every| last_stats.elapsed().as_secs() >= every);
+        if quit != quits || due {
+            quits = quit;
+            last_stats = time::Instant::now();
+            let ts = timefmt::prefix(opts.timestamp, start);
+            print_interim(&ts, dest, &ledger.snapshot(), &rtts);
+        }
+        // We actually report timed-out packets instead of just ignoring it.
+        // One-to-many requests stay until their deadline to collect every responder.
+        for seq in ledger.expire(time::Instant::now(), timeout) {
+            if !opts.compare {
+                println!(
+                    "{}\x1b[1;31mPacket Timed Out. {} Sequence Packet: {}\x1b[0m",
+                    timefmt::prefix(opts.timestamp, start),
+                    proto,
+                    seq
+                );
+            }
+        }
+        if ledger.finishing && ledger.probes.is_empty() {
+            break;
+        }
+        let event = match transport.recv(POLL_STEP) {
+            Some(event) => event,
+            None => continue,
+        };
+        let from = event.from;
+        let reply = match event.kind {
+            Kind::Reply(reply) => reply,
+            Kind::Error(msg) => {
+                let seq = ledger.unwrap(event.seq);
+                // `from` is whoever generated the error, usually a router on the way (a hop)
+                if let (true, Some(msg), false) = (ledger.error(seq), msg, opts.compare) {
+                    let from = from.map_or(String::new(), |ip| format!(" from {}", names.host(ip)));
+                    println!(
+                        "{}\x1b[1;31m{}{}. {} Sequence Packet: {}\x1b[0m",
+                        timefmt::prefix(opts.timestamp, start),
+                        msg,
+                        from,
+                        proto,
+                        seq
+                    );
                 }
+                continue;
             }
-            Err(_) => {
-                break;
+            Kind::Sent(stamp) => {
+                ledger.wait_for(&rx, event.seq);
+                ledger.restamp(ledger.unwrap(event.seq), stamp);
+                continue;
+            }
+        };
+        ledger.wait_for(&rx, event.seq);
+        let seq = ledger.unwrap(event.seq);
+        let verdict = ledger.reply(seq, from, reply.intact);
+        let ts = timefmt::prefix(opts.timestamp, start);
+        let mut info = reply_info(&reply, opts.tos);
+        let (what, how) = returned(reply.answer);
+        let (probe, first) = match verdict {
+            Verdict::Answer { probe, first } => (probe, first),
+            _ if opts.quite => continue,
+            Verdict::Corrupted => {
+                println!(
+                    "{}\x1b[1;31m{} {} corrupted (bad checksum). {} Sequence Packet: {}\x1b[0m",
+                    ts, what, how, proto, seq
+                );
+                continue;
+            }
+            Verdict::Duplicate => {
+                let host = responder(&mut names, from);
+                println!(
+                    "{}\x1b[1;33m{} {} again (duplicate) from {}. {} Sequence Packet: {}\x1b[0m",
+                    ts, what, how, host, proto, seq
+                );
+                continue;
+            }
+            // Its request already timed out, only the send time it carries can time it
+            Verdict::Late => {
+                let time = match reply.sent {
+                    Some(sent) => format!(
+                        ", \x1b[1;32mTime: \x1b[1;37m{:.3} ms",
+                        payload_rtt(sent, event.stamp).0.as_secs_f64() * 1000.0
+                    ),
+                    None => String::new(),
+                };
+                println!(
+                    "{}\x1b[1;33m{} {} late. {} Sequence Packet:\x1b[1;37m {}{}{}\x1b[0m",
+                    ts, what, how, proto, seq, time, info
+                );
+                continue;
             }
+        };
+        let (time, tx_source, rx_source) = rtt(&probe, event.stamp);
+        let time = time.as_secs_f64() * 1000.0;
+        if first {
+            rtts.push(time);
+        }
+        if opts.show_tstamp {
+            info.push_str(&format!(
+                ", \x1b[1;32mTimestamps: \x1b[1;37m{} tx, {} rx",
+                tx_source, rx_source
+            ));
+        }
+        if one_to_many {
+            if let Some(ip) = from {
+                *responders.entry(ip).or_insert(0) += 1;
+            }
+        }
+        if opts.quite {
+            continue;
+        }
+        if one_to_many || opts.rdns {
+            let host = responder(&mut names, from);
+            println!(
+                "{}\x1b[1;32m{} \x1b[37m{} from \x1b[1;37m{}\x1b[1;32m. {} Sequence Packet:\x1b[1;37m {}, \x1b[1;32mTime: \x1b[1;37m{:.3} ms{}\x1b[0m", ts, what, how, host, proto, seq, time, info
+            );
+        } else {
+            println!(
+                "{}\x1b[1;32m{} \x1b[37m{}. \x1b[1;32m{} Sequence Packet:\x1b[1;37m {}, \x1b[1;32mTime: \x1b[1;37m{:.3} ms{}\x1b[0m", ts, what, how, proto, seq, time, info
+            );
         }
     }
-    rtx
+    Returned {
+        stats: ledger.finish(),
+        responders,
+        names,
+        rtts,
+    }
 }
 
-pub fn run(opts: RingOptions, dest: SocketAddr) -> Result<(), RingError> {
-    let socket = opts.socket.try_clone()?;
-    let (tx, rx) = channel::<RingMessage>();
-
-    let mut echo = EchoICMP::new();
+// Who a printed reply came from. Only lines that get printed ask for a name (--rdns).
+fn responder(names: &mut Names, from: Option<IpAddr>) -> String {
+    from.map_or(String::from("?"), |ip| names.host(ip))
+}
 
-    // TEST: change
-    // This bit seems extremely hacky. I don't want to introduce new dependency for MPMC channel, as
-    // the std MPSC channel is not suitable for the task below.
-    //Also, use Condvar?
-    let cont = Arc::new(AtomicBool::new(true));
-    // Weirdly, you have to clone the `cont` variabel here. If cloned inside the `FnMut`, the compiler shouts
-    let cont_send = cont.clone();
+// A fresh lookup of the destination came in (--reresolve). Follow it when the address we ring is
+// gone from the answer, and keep ringing the old one when the lookup failed.
+fn follow(
+    transport: &dyn ProbeTransport,
+    host: &str,
+    dest: SocketAddr,
+    update: Result<Vec<SocketAddr>, RingError>,
+    seq: u64,
+    ts: &str,
+) -> Option<SocketAddr> {
+    let found = match update {
+        Ok(found) => found,
+        Err(e) => {
+            println!(
+                "{}\x1b[1;33m[EVENT]: Re-resolving failed, still ringing {}: {}\x1b[0m",
+                ts,
+                dest.ip(),
+                e
+            );
+            return None;
+        }
+    };
+    if found.iter().any(|a| a.ip() == dest.ip()) {
+        return None;
+    }
+    let new = found[0];
+    if let Err(e) = transport.connect(new) {
+        println!(
+            "{}\x1b[1;33m[EVENT]: {} moved to {}, but it can't be rung ({}), still ringing {}\x1b[0m",
+            ts,
+            host,
+            new.ip(),
+            e,
+            dest.ip()
+        );
+        return None;
+    }
+    println!(
+        "{}\x1b[1;33m[EVENT]: {} moved from {} to {}, ringing it from {} Sequence Packet {}\x1b[0m",
+        ts,
+        host,
+        dest.ip(),
+        new.ip(),
+        transport.protocol(),
+        seq
+    );
+    Some(new)
+}
 
-    // Condvar! YAY!
-    let pcond = Arc::new((Mutex::new(false), Condvar::new()));
-    let scond = pcond.clone();
+fn print_stats(stats: &RingStats, rtts: &[f64], pinged: u64) {
+    println!("\n\x1b[1;32m------------Ring Stats------------\x1b[0m");
+    println!(
+        "\n\x1b[1;32mRinged!\x1b[0m Received \x1b[1;32m{} packets\x1b[0m of  \x1b[1;32m{} total packets,\x1b[0m with \x1b[1;31m{}% loss!\x1b[0m Pinged for \x1b[1;32m{} seconds\x1b[0m.",
+        stats.received,
+        stats.transmitted,
+        stats.loss(),
+        pinged
+    );
+    // Only what actually happened, most rings have none of these
+    let details: Vec<String> = [
+        (stats.errors, "errors"),
+        (stats.timed_out, "timed out"),
+        (stats.in_flight, "still in flight"),
+        (stats.duplicates, "duplicates"),
+        (stats.corrupted, "corrupted"),
+        (stats.late, "late"),
+    ]
+    .iter()
+    .filter(|(n, _)| *n > 0)
+    .map(|(n, what)| format!("{n} {what}"))
+    .collect();
+    if !details.is_empty() {
+        println!("\x1b[1;33m{}\x1b[0m", details.join(", "));
+    }
+    if let Some((min, avg, max, mdev)) = rtt_stats(rtts) {
+        println!(
+            "RTT min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+            min, avg, max, mdev
+        );
+    }
+}
 
-    let recv_socket = socket.try_clone()?;
-    socket.set_nonblocking(true)?; // IMPORTANT
-
-    // Use a mut array of u8, so increasing the `seq_num` doesn't require creating a whole new copy of
-    // bytes.
-    let mut packet: [u8; DATA_LENGTH] = [0; DATA_LENGTH];
-    let ip = if !dest.is_ipv6() {
-        echo = EchoICMP::new_v4();
-        4u8
-    } else {
-        6u8
-    };
-    let interval = opts.interval;
-    let mut loop_time = opts.count as i64;
+// Summary of everyone who answered a broadcast or multicast ring
+fn print_responders(responders: HashMap<IpAddr, u32>, names: &mut Names) {
+    let mut responders: Vec<(IpAddr, u32)> = responders.into_iter().collect();
+    responders.sort();
+    println!("\n\x1b[1;32m{} host(s) responded:\x1b[0m", responders.len());
+    for (ip, replies) in responders {
+        println!(
+            "  \x1b[1;37m{}\x1b[0m: {} replies",
+            names.host_now(ip),
+            replies
+        );
+    }
+}
 
-    echo.init_bytes(&mut packet);
-    echo.increase_seq(&mut packet);
-    // seq 1
-    echo.update_bytes(&mut packet);
-    let handle = thread::spawn(move || handle_returned(rx, recv_socket, &opts));
+// Rings `dest` with `transport` (see `transport::open`), then lets go of the ring's socket
+pub fn run(
+    opts: RingOptions,
+    dest: SocketAddr,
+    stop: Interrupt,
+    transport: Arc<dyn ProbeTransport>,
+) -> Result<Summary, RingError> {
+    let socket = opts.socket.try_clone()?;
+    let summary = ring(opts, dest, stop, transport);
+    // Free Up the socket just in case
+    // Unconnected (one-to-many raw) sockets have nothing to shut down
+    _ = socket.shutdown(std::net::Shutdown::Both);
+    summary
+}
 
-    let tx_clone = tx.clone();
-    // The ctrlc crate takes a FnMut as an argument. We use Arc to store and load a boolen value to
-    // determine when to stop running the loop
-    ctrlc::set_handler(move || {
-        cont_send.store(false, std::sync::atomic::Ordering::Relaxed);
-        let (lock, cond) = &*scond;
-        // Unwrap seems good here. There is not much we can do if sending
-        // stop messege fails. The best thing to do would be to exit the program.
-        let mut lock = lock.lock().expect("Failed to aquire the lock");
-        *lock = true;
-        cond.notify_all();
-        tx_clone.send(RingMessage::Stop).unwrap();
-    })
-    .expect("Failed to register callback");
+// Rings `dest` with `transport` until the count runs out or `stop` is set. The sender and the
+// receiver thread share the transport.
+fn ring(
+    opts: RingOptions,
+    dest: SocketAddr,
+    stop: Interrupt,
+    transport: Arc<dyn ProbeTransport>,
+) -> Result<Summary, RingError> {
+    let (tx, rx) = channel::<RingMessage>();
+    let mut seq: u64 = 1;
+    let interval = opts.interval;
+    // The first requests of a preload (-l) go out without waiting in between
+    let mut burst = opts.preload - 1;
+    let mut current = dest;
+    let host = opts.addr.clone();
+    let updates = opts.reresolve.map(|every| {
+        resolver::watch(
+            host.clone(),
+            resolver::family_of(&dest),
+            opts.resolver,
+            time::Duration::from_secs(every),
+        )
+    });
+    // (when, from, to) for every time the destination moved
+    let mut moves: Vec<(time::Duration, SocketAddr, SocketAddr)> = Vec::new();
+    let one_to_many = opts.broadcast || opts.multicast;
+    let compare = opts.compare;
+    let mut loop_time = opts.count;
 
-    // Starts measuring and taking stats
-    // We initialize the stat struct here to be as correct as possible while measuring the time taken.
-    // If we start early, the internal calculations may dilute the time
-    let mut stats = RingStats::default();
+    let start = time::Instant::now();
+    let ts_format = opts.timestamp;
+    let receiver = transport.clone();
+    let handle = thread::spawn(move || handle_returned(rx, receiver, &opts, dest, start));
 
-    let (lock, cond) = &*pcond;
+    // Condvar! YAY!
+    let (lock, cond) = &*stop;
 
     loop {
         loop_time -= 1;
+        if let Some(update) = updates.as_ref().and_then(|u| u.try_recv().ok()) {
+            if let Some(new) = follow(
+                &*transport,
+                &host,
+                current,
+                update,
+                seq,
+                &timefmt::prefix(ts_format, start),
+            ) {
+                moves.push((start.elapsed(), current, new));
+                current = new;
+            }
+        }
         let time = time::Instant::now();
-        socket.send(&packet)?;
-        if tx
-            .send(RingMessage::Continue((echo.seq_num, time)))
-            .is_err()
-        {
+        let wall = time::SystemTime::now();
+        let sent_at = transport.send(seq, current)?.unwrap_or(Stamp::user(wall));
+        let probe = Probe {
+            seq,
+            sent: time,
+            sent_at,
+        };
+        if tx.send(RingMessage::Continue(probe)).is_err() {
             return Err(RingError::ChannelSendError);
         };
-        stats.packet_sent += 1;
-        echo.increase_seq(&mut packet);
-        if ip == 4 {
-            echo.update_bytes(&mut packet);
-        }
-        let mut lock = lock.lock().unwrap();
-        let res = cond
-            .wait_timeout(lock, time::Duration::from_secs(interval))
-            .unwrap();
-        lock = res.0;
+        seq += 1;
+        // Wakes up early when Ctrl + C is pressed, and then tells the receiver to stop too
+        let wait = if burst > 0 {
+            burst -= 1;
+            time::Duration::ZERO
+        } else {
+            time::Duration::from_secs(interval)
+        };
+        let lock = lock.lock().unwrap();
+        let (lock, _) = cond.wait_timeout_while(lock, wait, |stop| !*stop).unwrap();
         if *lock || (loop_time == 0) {
-            if let Err(e) = tx.send(RingMessage::Stop) {
+            let last = match *lock {
+                true => RingMessage::Abort,
+                false => RingMessage::Stop,
+            };
+            if let Err(e) = tx.send(last) {
                 eprintln!("{}", e);
                 return Err(RingError::ChannelSendError);
             }
@@ -309,32 +873,491 @@ pub fn run(opts: RingOptions, dest: SocketAddr) -> Result<(), RingError> {
         }
     }
     // Add code here to give the diagnostics result of all the times Pinged.
-    let (success, loss, discard) = match handle.join() {
-        Ok((s, l, d)) => (s, l, d),
-        Err(_) => (0, 0, 0),
-    };
-    if stats.packet_sent == 0 {
-        stats.packet_sent = 1;
+    let Returned {
+        stats,
+        responders,
+        mut names,
+        rtts,
+    } = handle.join().unwrap_or_default();
+    let pinged = start.elapsed().as_secs();
+    if compare {
+        return Ok(Summary { dest, stats, rtts });
     }
-    stats.packet_sent -= discard;
-    if success > stats.packet_sent {
-        stats.packet_sent += 1;
+    print_stats(&stats, &rtts, pinged);
+    if one_to_many {
+        print_responders(responders, &mut names);
     }
-    stats.loss = loss + (stats.packet_sent - success);
-    if stats.loss > stats.packet_sent {
-        stats.packet_sent = stats.loss;
+    if !moves.is_empty() {
+        println!(
+            "\n\x1b[1;33mThe destination moved {} time(s):\x1b[0m",
+            moves.len()
+        );
+        for (when, from, to) in moves {
+            println!("  after {}s: {} -> {}", when.as_secs(), from.ip(), to.ip());
+        }
     }
-    stats.successful = success;
-    println!("\n\x1b[1;32m------------Ring Stats------------\x1b[0m");
-    println!(
-        "\n\x1b[1;32mRinged!\x1b[0m Received \x1b[1;32m{} packets\x1b[0m of  \x1b[1;32m{} total packets,\x1b[0m with \x1b[1;31m{}% loss!\x1b[0m Pinged for \x1b[1;32m{} seconds\x1b[0m.",
-        stats.successful,
-        stats.packet_sent,
-        ((stats.loss * 100) / stats.packet_sent),
-        stats.time.elapsed().as_secs()
-    );
+    Ok(Summary { dest, stats, rtts })
+}
 
-    // Free Up the socket just in case
-    socket.shutdown(std::net::Shutdown::Both)?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iputils::sim::{Fate, SimNet};
+    use crate::transport::mock::{self, MockTransport};
+    use crate::transport::IcmpTransport;
+    use socket2::{Domain, Socket, Type};
+    use std::net::Ipv4Addr;
+
+    const TIMEOUT: time::Duration = time::Duration::from_millis(100);
+
+    fn probe(seq: u64, sent: time::Instant) -> Probe {
+        Probe {
+            seq,
+            sent,
+            sent_at: Stamp::user(time::SystemTime::now()),
+        }
+    }
+
+    fn host(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)))
+    }
+
+    // A unicast ring with requests 1..=n sent at `at`
+    fn ledger(n: u64, at: time::Instant) -> Ledger {
+        let mut l = Ledger::new(false);
+        for seq in 1..=n {
+            l.sent(probe(seq, at));
+        }
+        l
+    }
+
+    #[test]
+    fn all_answered() {
+        let mut l = ledger(3, time::Instant::now());
+        for seq in 1..=3 {
+            assert!(matches!(
+                l.reply(seq, host(1), true),
+                Verdict::Answer { first: true, .. }
+            ));
+        }
+        let stats = l.finish();
+        assert_eq!((stats.transmitted, stats.received), (3, 3));
+        assert_eq!(stats.loss(), 0);
+    }
+
+    #[test]
+    fn timeouts() {
+        let start = time::Instant::now();
+        let mut l = ledger(3, start);
+        l.reply(2, host(1), true);
+        assert!(l.expire(start, TIMEOUT).is_empty());
+        assert_eq!(l.expire(start + TIMEOUT, TIMEOUT), vec![1, 3]);
+        let stats = l.finish();
+        assert_eq!(
+            (stats.received, stats.timed_out, stats.in_flight),
+            (1, 2, 0)
+        );
+        assert_eq!(stats.loss(), 66);
+    }
+
+    #[test]
+    fn duplicates_dont_count_as_received() {
+        let mut l = ledger(2, time::Instant::now());
+        l.reply(1, host(1), true);
+        assert_eq!(l.reply(1, host(1), true), Verdict::Duplicate);
+        assert_eq!(l.reply(1, host(1), true), Verdict::Duplicate);
+        l.reply(2, host(1), true);
+        let stats = l.finish();
+        assert_eq!((stats.received, stats.duplicates), (2, 2));
+        assert_eq!(stats.loss(), 0);
+    }
+
+    #[test]
+    fn corrupted_reply_keeps_request_waiting() {
+        let start = time::Instant::now();
+        let mut l = ledger(2, start);
+        assert_eq!(l.reply(1, host(1), false), Verdict::Corrupted);
+        assert!(matches!(l.reply(1, host(1), true), Verdict::Answer { .. }));
+        assert_eq!(l.reply(2, host(1), false), Verdict::Corrupted);
+        assert_eq!(l.expire(start + TIMEOUT, TIMEOUT), vec![2]);
+        let stats = l.finish();
+        assert_eq!(
+            (stats.received, stats.corrupted, stats.timed_out),
+            (1, 2, 1)
+        );
+    }
+
+    #[test]
+    fn late_reply_stays_lost() {
+        let start = time::Instant::now();
+        let mut l = ledger(1, start);
+        l.expire(start + TIMEOUT, TIMEOUT);
+        assert_eq!(l.reply(1, host(1), true), Verdict::Late);
+        let stats = l.finish();
+        assert_eq!((stats.received, stats.timed_out, stats.late), (0, 1, 1));
+        assert_eq!(stats.loss(), 100);
+    }
+
+    #[test]
+    fn unicast_error_settles_at_once() {
+        let mut l = ledger(2, time::Instant::now());
+        assert!(l.error(1));
+        // Only one error per request, and none for requests we don't know
+        assert!(!l.error(1));
+        assert!(!l.error(7));
+        assert_eq!(l.reply(1, host(1), true), Verdict::Late);
+        l.reply(2, host(1), true);
+        let stats = l.finish();
+        assert_eq!((stats.received, stats.errors, stats.late), (1, 1, 1));
+        assert_eq!(stats.loss(), 50);
+    }
+
+    #[test]
+    fn in_flight_at_exit_is_not_loss() {
+        let mut l = ledger(4, time::Instant::now());
+        l.reply(1, host(1), true);
+        l.reply(2, host(1), true);
+        assert_eq!(l.in_flight(), 2);
+        assert_eq!(l.snapshot().in_flight, 2);
+        let stats = l.finish();
+        assert_eq!((stats.received, stats.in_flight), (2, 2));
+        assert_eq!(stats.settled(), 2);
+        assert_eq!(stats.loss(), 0);
+    }
+
+    #[test]
+    fn nothing_sent() {
+        let stats = Ledger::new(false).finish();
+        assert_eq!(stats, RingStats::default());
+        assert_eq!(stats.loss(), 0);
+    }
+
+    #[test]
+    fn one_to_many_counts_each_request_once() {
+        let start = time::Instant::now();
+        let mut l = Ledger::new(true);
+        l.sent(probe(1, start));
+        l.sent(probe(2, start));
+        assert!(matches!(
+            l.reply(1, host(1), true),
+            Verdict::Answer { first: true, .. }
+        ));
+        assert!(matches!(
+            l.reply(1, host(2), true),
+            Verdict::Answer { first: false, .. }
+        ));
+        assert_eq!(l.reply(1, host(2), true), Verdict::Duplicate);
+        // Answered requests only wait for more responders
+        assert_eq!(l.in_flight(), 1);
+        assert_eq!(l.expire(start + TIMEOUT, TIMEOUT), vec![2]);
+        assert_eq!(l.reply(1, host(3), true), Verdict::Duplicate);
+        let stats = l.finish();
+        assert_eq!((stats.transmitted, stats.received), (2, 1));
+        assert_eq!((stats.duplicates, stats.timed_out), (2, 1));
+    }
+
+    #[test]
+    fn one_to_many_error_waits_for_other_responders() {
+        let start = time::Instant::now();
+        let mut l = Ledger::new(true);
+        l.sent(probe(1, start));
+        l.sent(probe(2, start));
+        assert!(l.error(1));
+        assert!(l.error(2));
+        assert!(matches!(l.reply(2, host(1), true), Verdict::Answer { .. }));
+        assert_eq!(l.snapshot().errors, 1);
+        assert!(l.expire(start + TIMEOUT, TIMEOUT).is_empty());
+        let stats = l.finish();
+        assert_eq!((stats.received, stats.errors, stats.timed_out), (1, 1, 0));
+    }
+
+    #[test]
+    fn loss_never_exceeds_100() {
+        let start = time::Instant::now();
+        let mut l = ledger(3, start);
+        l.error(1);
+        l.expire(start + TIMEOUT, TIMEOUT);
+        for _ in 0..5 {
+            l.reply(2, host(1), true);
+            l.reply(3, host(1), false);
+            l.error(3);
+        }
+        let stats = l.finish();
+        assert_eq!(stats.settled(), 3);
+        assert_eq!(stats.lost(), 3);
+        assert_eq!(stats.loss(), 100);
+        assert_eq!(
+            stats.received + stats.lost() + stats.in_flight,
+            stats.transmitted
+        );
+    }
+
+    #[test]
+    fn sequences_unwrap_across_rollover() {
+        let start = time::Instant::now();
+        let mut l = Ledger::new(false);
+        for seq in 65534..=65538 {
+            l.sent(probe(seq, start));
+        }
+        // Wire sequences 65534, 65535, 0, 1, 2
+        assert_eq!(l.unwrap(65535), 65535);
+        assert_eq!(l.unwrap(0), 65536);
+        assert_eq!(l.unwrap(2), 65538);
+        for wire in [0, 65534, 2, 65535, 1] {
+            let seq = l.unwrap(wire);
+            assert!(
+                matches!(l.reply(seq, host(1), true), Verdict::Answer { probe, .. } if probe.seq == seq)
+            );
+        }
+        let stats = l.finish();
+        assert_eq!((stats.received, stats.duplicates), (5, 0));
+    }
+
+    #[test]
+    fn no_false_duplicates_after_rollover() {
+        let start = time::Instant::now();
+        let mut l = ledger(5, start);
+        l.reply(5, host(1), true);
+        l.expire(start + TIMEOUT, TIMEOUT);
+        // A whole wire sequence space later, sequence 5 is back on the wire
+        for seq in 6..=SEQ_SPACE + 5 {
+            l.sent(probe(seq, start + TIMEOUT));
+            l.reply(seq, host(1), true);
+        }
+        l.sent(probe(SEQ_SPACE + 6, start + TIMEOUT));
+        let seq = l.unwrap(6);
+        assert_eq!(seq, SEQ_SPACE + 6);
+        assert!(matches!(
+            l.reply(seq, host(1), true),
+            Verdict::Answer { .. }
+        ));
+        assert_eq!(l.reply(seq, host(1), true), Verdict::Duplicate);
+        let stats = l.finish();
+        assert_eq!(stats.transmitted as u64, SEQ_SPACE + 6);
+        assert_eq!(stats.received as u64, SEQ_SPACE + 2);
+        assert_eq!((stats.timed_out, stats.duplicates), (4, 1));
+    }
+
+    #[test]
+    fn answers_are_named_when_printed() {
+        assert_eq!(returned(Answer::Echo(21)), ("21 bytes".into(), "returned"));
+        let open = Answer::Port(443, PortState::Open);
+        assert_eq!(returned(open), ("Port 443".into(), "open"));
+        let refused = Answer::Port(22, PortState::Refused);
+        assert_eq!(returned(refused), ("Port 22".into(), "refused"));
+    }
+
+    #[test]
+    fn handover_and_abort() {
+        let (tx, rx) = channel();
+        let mut l = Ledger::new(false);
+        tx.send(RingMessage::Continue(probe(1, time::Instant::now())))
+            .unwrap();
+        tx.send(RingMessage::Continue(probe(2, time::Instant::now())))
+            .unwrap();
+        // A reply for request 2 can beat its handover
+        l.wait_for(&rx, 2);
+        assert_eq!(l.last, Some(2));
+        tx.send(RingMessage::Abort).unwrap();
+        l.drain(&rx);
+        assert!(l.aborted && !l.finishing);
+        drop(tx);
+        l.drain(&rx);
+        assert!(l.finishing);
+    }
+
+    // Rings `dest` quietly through the transport `open` makes for the ring's options. The
+    // requests go out back to back unless `interval` is set.
+    fn ring_test(
+        dest: &str,
+        count: i64,
+        interval: u64,
+        open: impl FnOnce(&RingOptions, SocketAddr) -> Arc<dyn ProbeTransport>,
+    ) -> Summary {
+        // Never used, but every ring carries a socket
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        let mut opts = RingOptions::with_socket(socket, false);
+        opts.count = count;
+        opts.interval = interval;
+        opts.timeout = TIMEOUT.as_millis();
+        opts.quite = true;
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let dest = dest.parse().unwrap();
+        let transport = open(&opts, dest);
+        ring(opts, dest, stop, transport).unwrap()
+    }
+
+    // Rings `dest` over a simulated network
+    fn ring_sim(
+        dest: &str,
+        count: i64,
+        interval: u64,
+        fate: impl Fn(u16) -> Fate + Send + Sync + 'static,
+    ) -> Summary {
+        ring_test(dest, count, interval, |opts, dest| {
+            Arc::new(IcmpTransport::new(
+                Arc::new(SimNet::new(fate)),
+                opts,
+                dest,
+                false,
+            ))
+        })
+    }
+
+    const FAST: time::Duration = time::Duration::from_millis(5);
+
+    #[test]
+    fn sim_all_answered() {
+        let summary = ring_sim("192.0.2.1:0", 5, 0, |_| Fate::Reply(FAST));
+        assert_eq!((summary.stats.transmitted, summary.stats.received), (5, 5));
+        assert_eq!(summary.stats.loss(), 0);
+        assert_eq!(summary.rtts.len(), 5);
+    }
+
+    #[test]
+    fn sim_rtt_is_the_latency() {
+        let latency = time::Duration::from_millis(30);
+        let summary = ring_sim("192.0.2.1:0", 3, 0, move |_| Fate::Reply(latency));
+        let (min, _, max, _) = summary.rtt_stats().unwrap();
+        assert!(min >= 30.0, "{min}");
+        assert!(max < TIMEOUT.as_secs_f64() * 1000.0, "{max}");
+    }
+
+    #[test]
+    fn sim_loss() {
+        let summary = ring_sim("192.0.2.1:0", 8, 0, |seq| match seq % 4 {
+            0 => Fate::Lost,
+            _ => Fate::Reply(FAST),
+        });
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.timed_out), (6, 2));
+        assert_eq!(stats.loss(), 25);
+    }
+
+    #[test]
+    fn sim_slower_than_timeout() {
+        let summary = ring_sim("192.0.2.1:0", 3, 0, |_| Fate::Reply(TIMEOUT * 2));
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.timed_out), (0, 3));
+        assert_eq!(stats.loss(), 100);
+        assert!(summary.rtts.is_empty());
+    }
+
+    #[test]
+    fn sim_late_reply() {
+        // The second request keeps the ring going long enough to see the first one's late reply
+        let summary = ring_sim("192.0.2.1:0", 2, 1, |seq| match seq {
+            1 => Fate::Reply(TIMEOUT + TIMEOUT / 2),
+            _ => Fate::Reply(FAST),
+        });
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.timed_out, stats.late), (1, 1, 1));
+    }
+
+    #[test]
+    fn sim_out_of_order() {
+        let summary = ring_sim("192.0.2.1:0", 3, 0, |seq| {
+            Fate::Reply(time::Duration::from_millis(45 - 15 * seq as u64))
+        });
+        assert_eq!(summary.stats.received, 3);
+        assert_eq!(summary.stats.late, 0);
+    }
+
+    #[test]
+    fn sim_duplicates() {
+        // Back to back, the ring would be over as soon as the first replies are in. With an
+        // interval it waits one more after the last request, which gives the duplicates time.
+        let summary = ring_sim("192.0.2.1:0", 2, 1, |_| Fate::Duplicate(FAST, FAST));
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.duplicates), (2, 2));
+        assert_eq!(stats.loss(), 0);
+        assert_eq!(summary.rtts.len(), 2);
+    }
+
+    #[test]
+    fn sim_corrupted() {
+        let summary = ring_sim("192.0.2.1:0", 4, 0, |seq| match seq {
+            1 => Fate::Corrupt(FAST),
+            _ => Fate::Reply(FAST),
+        });
+        let stats = summary.stats;
+        assert_eq!(
+            (stats.received, stats.corrupted, stats.timed_out),
+            (3, 1, 1)
+        );
+    }
+
+    #[test]
+    fn sim_unreachable() {
+        let summary = ring_sim("192.0.2.1:0", 3, 0, |seq| match seq {
+            2 => Fate::Unreachable(FAST),
+            _ => Fate::Reply(FAST),
+        });
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.errors, stats.timed_out), (2, 1, 0));
+        assert_eq!(stats.loss(), 33);
+    }
+
+    #[test]
+    fn sim_ipv6() {
+        let summary = ring_sim("[2001:db8::1]:0", 4, 0, |seq| match seq {
+            1 => Fate::Corrupt(FAST),
+            2 => Fate::Unreachable(FAST),
+            _ => Fate::Reply(FAST),
+        });
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.errors), (2, 1));
+        assert_eq!((stats.corrupted, stats.timed_out), (1, 1));
+    }
+
+    // Rings through a transport that answers from `script`, with no packets involved
+    fn ring_mock(
+        count: i64,
+        script: impl Fn(u64) -> Vec<(time::Duration, Kind)> + Send + Sync + 'static,
+    ) -> (Summary, Arc<MockTransport>) {
+        let transport = Arc::new(MockTransport::new(script));
+        let summary = ring_test("192.0.2.1:0", count, 0, |_, _| transport.clone());
+        (summary, transport)
+    }
+
+    #[test]
+    fn mock_sends_every_sequence() {
+        let (summary, transport) = ring_mock(5, |_| vec![(FAST, mock::reply())]);
+        let sent: Vec<u64> = transport.sent.lock().unwrap().iter().map(|s| s.0).collect();
+        assert_eq!(sent, [1, 2, 3, 4, 5]);
+        assert_eq!((summary.stats.transmitted, summary.stats.received), (5, 5));
+        assert_eq!(summary.rtts.len(), 5);
+    }
+
+    #[test]
+    fn mock_errors_are_lost() {
+        let (summary, _) = ring_mock(4, |seq| match seq % 2 {
+            1 => vec![(
+                FAST,
+                Kind::Error(Some("Destination Host Unreachable".into())),
+            )],
+            // Messages without a name still count
+            _ => vec![(FAST, Kind::Error(None))],
+        });
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.errors, stats.timed_out), (0, 4, 0));
+        assert_eq!(stats.loss(), 100);
+    }
+
+    #[test]
+    fn mock_extra_replies() {
+        let damaged = Kind::Reply(Reply {
+            intact: false,
+            ..Reply::default()
+        });
+        let (summary, _) = ring_mock(3, move |seq| match seq {
+            1 => vec![(FAST, mock::reply()), (FAST * 2, mock::reply())],
+            2 => vec![(FAST, damaged.clone())],
+            _ => vec![],
+        });
+        let stats = summary.stats;
+        assert_eq!((stats.received, stats.duplicates), (1, 1));
+        assert_eq!((stats.corrupted, stats.timed_out), (1, 2));
+        assert_eq!(stats.loss(), 66);
+    }
 }
@@ -0,0 +1,293 @@
+// Echo requests and replies (ping as everyone knows it), over an ICMP socket or a simulated network
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use socket2::SockAddr;
+
+use super::{Answer, Event, Kind, ProbeTransport, Reply};
+use crate::iputils::{
+    self,
+    icmp::{self, Echo, IcmpPacket, Version},
+    ipv4::Ipv4Header,
+    tstamp::{Source, Stamp},
+    wire::Wire,
+};
+use crate::{RingOptions, DATA};
+
+pub struct IcmpTransport {
+    wire: Arc<dyn Wire>,
+    version: Version,
+    // `SOCK_RAW` sockets see every ICMP packet, and IPv4 ones with the IP header in front
+    raw: bool,
+    // `SOCK_DGRAM` sockets fill in their own identifier
+    ident: u16,
+    // Put the send time and this nonce in every request (--embed-time)
+    embed: Option<u32>,
+    // Whether the kernel was asked to stamp outgoing requests
+    tx_stamps: bool,
+    // Held while reading TX stamps, so `send` and `recv` never take each other's
+    tx: Mutex<TxLog>,
+}
+
+// The kernel numbers TX stamps by the requests sent on the socket before them
+// (SOF_TIMESTAMPING_OPT_ID), not by their sequence
+#[derive(Default)]
+struct TxLog {
+    sent: u32,
+    // The wire sequence of the latest requests, by that number
+    seqs: VecDeque<(u32, u16)>,
+    // Stamps that came after `send` stopped waiting for them, a NIC's usually
+    late: VecDeque<(u32, Stamp)>,
+}
+
+// Late stamps show up within a few requests, if at all
+const TX_LOG: usize = 64;
+
+impl IcmpTransport {
+    pub fn new(wire: Arc<dyn Wire>, opts: &RingOptions, dest: SocketAddr, tx_stamps: bool) -> Self {
+        IcmpTransport {
+            wire,
+            version: match dest.is_ipv4() {
+                true => Version::V4,
+                false => Version::V6,
+            },
+            raw: opts.raw,
+            ident: match opts.raw {
+                true => iputils::raw_identifier(),
+                false => 0,
+            },
+            embed: opts.embed_time.then_some(opts.nonce),
+            tx_stamps,
+            tx: Mutex::new(TxLog::default()),
+        }
+    }
+
+    // A TX stamp that came late, for the request it belongs to
+    fn late_stamp(&self, read: bool) -> Option<Event> {
+        let mut tx = self.tx.lock().unwrap();
+        if read && tx.late.is_empty() {
+            let stamps = self.wire.tx_stamps(None);
+            tx.late.extend(stamps);
+        }
+        while let Some((id, stamp)) = tx.late.pop_front() {
+            if let Some(&(_, seq)) = tx.seqs.iter().find(|(i, _)| *i == id) {
+                return Some(Event {
+                    seq,
+                    from: None,
+                    stamp: None,
+                    kind: Kind::Sent(stamp),
+                });
+            }
+        }
+        None
+    }
+
+    // Our echo request an ICMP error quotes, if it quotes one
+    fn quoted(&self, packet: &IcmpPacket) -> Option<u16> {
+        let quote = match packet {
+            IcmpPacket::DestinationUnreachable(quote)
+            | IcmpPacket::TimeExceeded(quote)
+            | IcmpPacket::SourceQuench(quote)
+            | IcmpPacket::PacketTooBig { quote, .. }
+            | IcmpPacket::ParameterProblem { quote, .. } => quote,
+            _ => return None,
+        };
+        quote
+            .echo(self.version)
+            .filter(|echo| !self.raw || echo.ident == self.ident)
+            .map(|echo| echo.seq)
+    }
+}
+
+impl ProbeTransport for IcmpTransport {
+    fn send(&self, seq: u64, to: SocketAddr) -> io::Result<Option<Stamp>> {
+        let mut payload = *DATA;
+        if let Some(nonce) = self.embed {
+            iputils::embed_time(&mut payload, SystemTime::now(), nonce);
+        }
+        let request = IcmpPacket::EchoRequest(Echo {
+            ident: self.ident,
+            seq: seq as u16,
+            payload: &payload,
+        });
+        let packet = request.serialize(self.version, 0);
+        if !self.tx_stamps {
+            self.wire.send_to(&packet, &SockAddr::from(to))?;
+            return Ok(None);
+        }
+        let mut tx = self.tx.lock().unwrap();
+        self.wire.send_to(&packet, &SockAddr::from(to))?;
+        let id = tx.sent;
+        tx.sent = tx.sent.wrapping_add(1);
+        if tx.seqs.len() == TX_LOG {
+            tx.seqs.pop_front();
+        }
+        tx.seqs.push_back((id, seq as u16));
+        let mut own = None;
+        for (i, stamp) in self.wire.tx_stamps(Some(id)) {
+            match i == id && stamp.source == Source::Kernel {
+                true => own = Some(stamp),
+                // Including a NIC stamp of this request that beat the kernel's, which has no send
+                // time on the system clock of its own. It's merged in once the request is out.
+                false => tx.late.push_back((i, stamp)),
+            }
+        }
+        Ok(own)
+    }
+
+    fn recv(&self, timeout: Duration) -> Option<Event> {
+        if let Some(event) = self.late_stamp(false) {
+            return Some(event);
+        }
+        // Stamps left on the error queue cut the wait short without anything to read, until
+        // they're taken off it
+        if !self.wire.wait_readable(timeout) {
+            return match self.tx_stamps {
+                true => self.late_stamp(true),
+                false => None,
+            };
+        }
+        // Big enough for a full IPv4 header with options in front of an ICMP error, which quotes
+        // the offending datagram.
+        let mut buf = [0; 512];
+        let received = self.wire.recv_msg(&mut buf).ok()?;
+        let bytes = &buf[..received.len];
+        let from = received.from.map(|f| f.ip());
+
+        // When using raw sockets, we get ip header + icmp packet. Only required when
+        // using icmpv4 raw packets. isn't needed in dgram and icmpv6 packets.
+        let (header, bytes) = match (self.raw, self.version) {
+            (true, Version::V4) => {
+                let header = Ipv4Header::parse(bytes).ok()?;
+                let icmp = header.payload(bytes);
+                (Some(header), icmp)
+            }
+            _ => (None, bytes),
+        };
+        let packet = IcmpPacket::parse(bytes, self.version).ok()?;
+        let reply = match packet {
+            IcmpPacket::EchoReply(reply) if !self.raw || reply.ident == self.ident => reply,
+            // If the packet isn't ICMP echo reply, find the request it is about. Raw sockets also
+            // see our own requests on loopback and replies meant for other pings.
+            _ => {
+                return Some(Event {
+                    seq: self.quoted(&packet)?,
+                    from,
+                    stamp: received.stamp,
+                    kind: Kind::Error(describe(&packet, self.version)),
+                })
+            }
+        };
+
+        let embedded = self.embed.and(iputils::read_embedded(reply.payload));
+        // A reply to an earlier ring that happened to use the same identifier
+        if self.raw && embedded.is_some_and(|(_, nonce)| Some(nonce) != self.embed) {
+            return None;
+        }
+        // ICMPv6 replies can only be checked when the kernel told us both addresses
+        let intact = match (self.version, from, received.to) {
+            (Version::V4, _, _) => icmp::verify(bytes, 0).is_ok(),
+            (_, Some(IpAddr::V6(src)), Some(IpAddr::V6(dst))) => {
+                icmp::verify(bytes, icmp::pseudo_sum(&src, &dst, bytes.len())).is_ok()
+            }
+            _ => true,
+        };
+        Some(Event {
+            seq: reply.seq,
+            from,
+            stamp: received.stamp,
+            kind: Kind::Reply(Reply {
+                answer: Answer::Echo(reply.payload.len()),
+                intact,
+                sent: embedded.map(|(sent, _)| sent),
+                ttl: header.as_ref().map(|h| h.ttl),
+                tos: received.tos.or(header.as_ref().map(|h| h.tos)),
+                options: header.as_ref().map_or(0, |h| h.options.len()),
+            }),
+        })
+    }
+
+    fn connect(&self, to: SocketAddr) -> io::Result<()> {
+        self.wire.connect(&SockAddr::from(to))
+    }
+
+    fn protocol(&self) -> &'static str {
+        "ICMP"
+    }
+}
+
+// Name ICMP error messages. See rfc 792 and rfc 4443
+fn describe(packet: &IcmpPacket, version: Version) -> Option<String> {
+    let msg = match (packet, version) {
+        (IcmpPacket::DestinationUnreachable(q), Version::V4) => match q.code {
+            0 => "Destination Network Unreachable",
+            1 => "Destination Host Unreachable",
+            2 => "Destination Protocol Unreachable",
+            3 => "Destination Port Unreachable",
+            4 => "Fragmentation Needed",
+            5 => "Source Route Failed",
+            _ => return None,
+        },
+        (IcmpPacket::DestinationUnreachable(q), Version::V6) => match q.code {
+            0 => "No Route to Destination",
+            1 => "Communication Administratively Prohibited",
+            2 => "Beyond Scope of Source Address",
+            3 => "Destination Address Unreachable",
+            4 => "Destination Port Unreachable",
+            5 => "Source Address Failed Policy",
+            6 => "Reject Route to Destination",
+            _ => return None,
+        },
+        (IcmpPacket::SourceQuench(q), _) if q.code == 0 => "Source Quench",
+        (IcmpPacket::TimeExceeded(q), _) => match (q.code, version) {
+            (0, Version::V4) => "Time to Live Exceeded",
+            (0, Version::V6) => "Hop Limit Exceeded",
+            (1, _) => "Fragementation limit Exceeded",
+            _ => return None,
+        },
+        (IcmpPacket::PacketTooBig { mtu, .. }, _) => {
+            return Some(format!("Packet Too Big (MTU {})", mtu))
+        }
+        (IcmpPacket::ParameterProblem { .. }, _) => "Parameter Problem",
+        _ => return None,
+    };
+    Some(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iputils::tstamp;
+    use socket2::{Domain, Socket, Type};
+    use std::net::UdpSocket;
+    use std::time::Instant;
+
+    #[test]
+    fn stamps_left_on_the_error_queue_dont_spin_the_receiver() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dest = peer.local_addr().unwrap();
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        socket.connect(&dest.into()).unwrap();
+        if !tstamp::enable(&socket) {
+            return;
+        }
+        socket.set_nonblocking(true).unwrap();
+        // Nobody reads this one's TX stamp, like a NIC's that comes after `send` stopped waiting
+        socket.send(b"0").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        let opts = RingOptions::with_socket(socket.try_clone().unwrap(), false);
+        let transport = IcmpTransport::new(Arc::new(socket), &opts, dest, true);
+        let start = Instant::now();
+        let mut calls = 0;
+        while start.elapsed() < Duration::from_millis(100) {
+            assert_eq!(transport.recv(Duration::from_millis(20)), None);
+            calls += 1;
+        }
+        assert!(calls <= 6, "{calls} receives in 100 ms");
+    }
+}
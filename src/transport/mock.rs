@@ -0,0 +1,72 @@
+// A transport that answers from a script instead of the network, for tests of the loop itself:
+// scheduling, stats and what gets printed, with no packets to build or parse.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{Answer, Event, Kind, ProbeTransport, Reply};
+use crate::iputils::{sim::Timed, tstamp::Stamp};
+
+// What comes back for a request, and how long after it was sent
+type Script = dyn Fn(u64) -> Vec<(Duration, Kind)> + Send + Sync;
+
+pub struct MockTransport {
+    script: Box<Script>,
+    // Events not yet handed to the ring
+    queue: Timed<Event>,
+    // Every request sent, in order
+    pub sent: Mutex<Vec<(u64, SocketAddr)>>,
+}
+
+impl MockTransport {
+    pub fn new(script: impl Fn(u64) -> Vec<(Duration, Kind)> + Send + Sync + 'static) -> Self {
+        MockTransport {
+            script: Box::new(script),
+            queue: Timed::new(),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+// An intact reply with nothing else to tell
+pub fn reply() -> Kind {
+    Kind::Reply(Reply {
+        answer: Answer::Echo(21),
+        intact: true,
+        ..Reply::default()
+    })
+}
+
+impl ProbeTransport for MockTransport {
+    fn send(&self, seq: u64, to: SocketAddr) -> io::Result<Option<Stamp>> {
+        self.sent.lock().unwrap().push((seq, to));
+        for (after, kind) in (self.script)(seq) {
+            let event = Event {
+                seq: seq as u16,
+                from: Some(to.ip()),
+                stamp: None,
+                kind,
+            };
+            self.queue.push(after, event);
+        }
+        Ok(None)
+    }
+
+    fn recv(&self, timeout: Duration) -> Option<Event> {
+        match self.queue.wait(timeout) {
+            true => self.queue.pop(),
+            false => None,
+        }
+    }
+
+    fn connect(&self, _: SocketAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Looks like an ICMP ring from the outside
+    fn protocol(&self) -> &'static str {
+        "ICMP"
+    }
+}
@@ -0,0 +1,115 @@
+// What a ring sends its requests with. The loop in `ring_impl` only schedules requests, matches what
+// comes back to them and keeps the stats, so a new kind of probe is a new `ProbeTransport`.
+//
+// - `icmp`: echo requests over an ICMP socket (or anything else that is a `Wire`)
+// - `tcp`: handshakes with a TCP port (--tcp)
+// - `mock`: scripted events, for testing the loop itself
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::error::RingError;
+use crate::iputils::{
+    recv,
+    tstamp::{self, Stamp},
+};
+use crate::RingOptions;
+
+pub mod icmp;
+#[cfg(test)]
+pub mod mock;
+pub mod tcp;
+
+pub use self::icmp::IcmpTransport;
+pub use self::tcp::TcpTransport;
+
+// The transport the options ask for, set up to ring `dest`. ICMP rings go over the ring's socket.
+pub fn open(opts: &RingOptions, dest: SocketAddr) -> Result<Arc<dyn ProbeTransport>, RingError> {
+    if let Some(port) = opts.tcp {
+        return Ok(Arc::new(TcpTransport::new(opts, port)?));
+    }
+    let socket = opts.socket.try_clone()?;
+    recv::enable_recv_tos(&socket)?;
+    recv::enable_recv_dest(&socket)?;
+    let tx_stamps = tstamp::enable(&socket);
+    socket.set_nonblocking(true)?; // IMPORTANT
+    Ok(Arc::new(IcmpTransport::new(
+        Arc::new(socket),
+        opts,
+        dest,
+        tx_stamps,
+    )))
+}
+
+pub trait ProbeTransport: Send + Sync {
+    // Send request `seq` to `to`. Returns when it left, if the kernel (or NIC) told us.
+    fn send(&self, seq: u64, to: SocketAddr) -> io::Result<Option<Stamp>>;
+    // Wait up to `timeout` for something to come back. `None` when nothing did, or nothing of ours.
+    fn recv(&self, timeout: Duration) -> Option<Event>;
+    // The destination moved (--reresolve), send the next requests to `to`
+    fn connect(&self, to: SocketAddr) -> io::Result<()>;
+    // Named in every line about a request, e.g. "ICMP Sequence Packet: 3"
+    fn protocol(&self) -> &'static str;
+}
+
+// Something that came back for one of our requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    // The low 16 bits of the request's sequence, which is all most probes carry
+    pub seq: u16,
+    pub from: Option<IpAddr>,
+    // When it arrived, if the kernel (or NIC) stamped it
+    pub stamp: Option<Stamp>,
+    pub kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Reply(Reply),
+    // A router on the way (or the destination) says the request can't get through. `None` for
+    // messages we have no name for.
+    Error(Option<String>),
+    // When the request left, known only after `send` returned (NICs stamp late)
+    Sent(Stamp),
+}
+
+// The destination answered
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Reply {
+    pub answer: Answer,
+    // False when the reply got damaged on the way (bad checksum)
+    pub intact: bool,
+    // The send time the reply carried back (--embed-time)
+    pub sent: Option<SystemTime>,
+    // Only known when the IPv4 header came along with the reply
+    pub ttl: Option<u8>,
+    // TOS or traffic class the reply arrived with
+    pub tos: Option<u8>,
+    // Bytes of IP options the reply carried
+    pub options: usize,
+}
+
+// What the destination answered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    // An echo reply with this many bytes of payload
+    Echo(usize),
+    // How the TCP port took the handshake (--tcp). Those replies carry no payload.
+    Port(u16, PortState),
+}
+
+impl Default for Answer {
+    fn default() -> Self {
+        Answer::Echo(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    // The handshake completed
+    Open,
+    // The host answered with a reset
+    Refused,
+}
@@ -0,0 +1,210 @@
+// TCP connect rings (--tcp <port>), for hosts that drop ICMP. Every request is a non-blocking
+// connect from its own socket, timed from the SYN until the handshake completes (the SYN/ACK came
+// back, the port is open) or the host answers with a reset (the port is refused). Either way the
+// host answered, so both count as received.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use super::{Answer, Event, Kind, PortState, ProbeTransport, Reply};
+use crate::iputils::tstamp::Stamp;
+use crate::RingOptions;
+
+// A connect still waiting for an answer
+struct Handshake {
+    seq: u16,
+    to: SocketAddr,
+    socket: Socket,
+    started: Instant,
+}
+
+pub struct TcpTransport {
+    port: u16,
+    ttl: Option<u32>,
+    tos: Option<u8>,
+    // The source address the route to the destination picked, -I and --vrf included
+    source: Option<IpAddr>,
+    // -I <iface> or --vrf, which every socket has to be bound to
+    device: Option<String>,
+    // Handshakes still pending this long after they started are given up. Twice the timeout, so
+    // answers that come a little late still show up as late.
+    linger: Duration,
+    pending: Mutex<Vec<Handshake>>,
+    // Answers known right at `connect`, like a local "no route to host"
+    ready: Mutex<VecDeque<Event>>,
+    // `send` pokes this to wake a waiting `recv`, so the new handshake gets polled (and timed)
+    // right away instead of at the next poll step
+    wake: (UnixStream, UnixStream),
+}
+
+impl TcpTransport {
+    pub fn new(opts: &RingOptions, port: u16) -> io::Result<Self> {
+        let wake = UnixStream::pair()?;
+        wake.0.set_nonblocking(true)?;
+        wake.1.set_nonblocking(true)?;
+        let device = match &opts.interface {
+            Some(iface) if iface.parse::<IpAddr>().is_err() => Some(iface.clone()),
+            _ => opts.vrf.clone(),
+        };
+        Ok(TcpTransport {
+            port,
+            ttl: opts.ttl,
+            tos: opts.tos,
+            // Connected by `prepare` in main, so the kernel already picked it
+            source: opts
+                .socket
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_socket())
+                .map(|a| a.ip())
+                .filter(|ip| !ip.is_unspecified()),
+            device,
+            linger: Duration::from_millis(opts.timeout as u64) * 2,
+            pending: Mutex::new(Vec::new()),
+            ready: Mutex::new(VecDeque::new()),
+            wake,
+        })
+    }
+
+    fn socket(&self, to: SocketAddr) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(to), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        if let Some(device) = &self.device {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        if let Some(ip) = self.source {
+            socket.bind(&SockAddr::from(SocketAddr::new(ip, 0)))?;
+        }
+        match (self.ttl, to.is_ipv4()) {
+            (Some(ttl), true) => socket.set_ttl(ttl)?,
+            (Some(ttl), false) => socket.set_unicast_hops_v6(ttl)?,
+            _ => {}
+        }
+        match (self.tos, to.is_ipv4()) {
+            (Some(tos), true) => socket.set_tos(tos as u32)?,
+            (Some(tos), false) => socket.set_tclass_v6(tos as u32)?,
+            _ => {}
+        }
+        Ok(socket)
+    }
+
+    // What a finished handshake to `to` means for request `seq`. Connect errors don't tell which
+    // router sent them, so those come from nobody in particular.
+    fn event(&self, seq: u16, to: SocketAddr, result: Option<io::Error>) -> Event {
+        let (from, kind) = match result {
+            None => (Some(to.ip()), self.reply(PortState::Open)),
+            Some(e) if e.raw_os_error() == Some(libc::ECONNREFUSED) => {
+                (Some(to.ip()), self.reply(PortState::Refused))
+            }
+            Some(e) => (None, Kind::Error(Some(describe(&e)))),
+        };
+        Event {
+            seq,
+            from,
+            stamp: Some(Stamp::user(SystemTime::now())),
+            kind,
+        }
+    }
+
+    fn reply(&self, state: PortState) -> Kind {
+        Kind::Reply(Reply {
+            answer: Answer::Port(self.port, state),
+            intact: true,
+            ..Reply::default()
+        })
+    }
+}
+
+impl ProbeTransport for TcpTransport {
+    fn send(&self, seq: u64, to: SocketAddr) -> io::Result<Option<Stamp>> {
+        // Re-resolved destinations (--reresolve) come without the port
+        let to = SocketAddr::new(to.ip(), self.port);
+        let socket = self.socket(to)?;
+        match socket.connect(&SockAddr::from(to)) {
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {
+                self.pending.lock().unwrap().push(Handshake {
+                    seq: seq as u16,
+                    to,
+                    socket,
+                    started: Instant::now(),
+                });
+                _ = (&self.wake.0).write(&[0]);
+            }
+            // Loopback handshakes can finish on the spot, and local routing errors fail on it
+            result => {
+                let event = self.event(seq as u16, to, result.err());
+                self.ready.lock().unwrap().push_back(event);
+            }
+        }
+        Ok(None)
+    }
+
+    fn recv(&self, timeout: Duration) -> Option<Event> {
+        if let Some(event) = self.ready.lock().unwrap().pop_front() {
+            return Some(event);
+        }
+        // Only `recv` closes sockets, so their descriptors stay valid without holding the lock
+        // while waiting, which would hold up `send`
+        let mut fds = vec![libc::pollfd {
+            fd: self.wake.1.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        fds.extend(self.pending.lock().unwrap().iter().map(|h| libc::pollfd {
+            fd: h.socket.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        }));
+        let res = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if res > 0 && fds[0].revents != 0 {
+            let mut buf = [0; 64];
+            while matches!((&self.wake.1).read(&mut buf), Ok(n) if n > 0) {}
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        let mut ready = self.ready.lock().unwrap();
+        for pfd in fds.iter().skip(1).filter(|p| p.revents != 0) {
+            let Some(i) = pending.iter().position(|h| h.socket.as_raw_fd() == pfd.fd) else {
+                continue;
+            };
+            let handshake = pending.swap_remove(i);
+            let result = handshake.socket.take_error().unwrap_or_else(Some);
+            ready.push_back(self.event(handshake.seq, handshake.to, result));
+        }
+        pending.retain(|h| h.started.elapsed() < self.linger);
+        ready.pop_front()
+    }
+
+    // Every request connects on its own, there is nothing to move
+    fn connect(&self, _: SocketAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn protocol(&self) -> &'static str {
+        "TCP"
+    }
+}
+
+// Name the ways a connect can fail, like the ICMP errors behind most of them
+fn describe(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(libc::EHOSTUNREACH) => "Destination Host Unreachable".to_string(),
+        Some(libc::ENETUNREACH) => "Destination Network Unreachable".to_string(),
+        Some(libc::ETIMEDOUT) => "Connection Timed Out".to_string(),
+        Some(libc::EACCES) | Some(libc::EPERM) => "Connection Prohibited".to_string(),
+        _ => e.to_string(),
+    }
+}
@@ -0,0 +1,236 @@
+// Runs the ring binary against the loopback addresses. Unprivileged ICMP sockets are off on some
+// hosts (net.ipv4.ping_group_range) and IPv6 on others, so tests that need to ring skip themselves
+// when ring can't open its socket. TCP rings need neither, so those always run.
+
+use std::net::TcpListener;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+fn ring(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ring"))
+        .args(args)
+        .output()
+        .expect("ring should start")
+}
+
+// Like `ring`, but None (and a note on stderr) when the host doesn't let ring open its socket
+fn ring_or_skip(args: &[&str]) -> Option<Output> {
+    let out = ring(args);
+    if !out.status.success() && stderr(&out).contains("Can't open an") {
+        eprintln!(
+            "skipping `ring {}`: {}",
+            args.join(" "),
+            stderr(&out).trim()
+        );
+        return None;
+    }
+    Some(out)
+}
+
+// Output without the colors
+fn plain(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().take_while(|&c| c != 'm').for_each(drop);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn stdout(out: &Output) -> String {
+    plain(&out.stdout)
+}
+
+fn stderr(out: &Output) -> String {
+    plain(&out.stderr)
+}
+
+// The sequence numbers of the replies
+fn replies(out: &Output) -> Vec<u64> {
+    stdout(out)
+        .lines()
+        .filter(|l| l.contains("returned"))
+        .filter_map(|l| l.split("ICMP Sequence Packet: ").nth(1))
+        .filter_map(|rest| rest.split(',').next()?.trim().parse().ok())
+        .collect()
+}
+
+// (received, total, loss %) from the summary
+fn summary(out: &Output) -> (u32, u32, u32) {
+    let text = stdout(out);
+    let line = text
+        .lines()
+        .find(|l| l.starts_with("Ringed!"))
+        .expect("a summary");
+    let numbers: Vec<u32> = line
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    (numbers[0], numbers[1], numbers[2])
+}
+
+#[test]
+fn count_ipv4() {
+    let Some(out) = ring_or_skip(&["-c", "3", "-i", "0", "127.0.0.1"]) else {
+        return;
+    };
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(replies(&out), vec![1, 2, 3]);
+    assert_eq!(summary(&out), (3, 3, 0));
+}
+
+#[test]
+fn count_ipv6() {
+    let Some(out) = ring_or_skip(&["-6", "-c", "3", "-i", "0", "::1"]) else {
+        return;
+    };
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(replies(&out), vec![1, 2, 3]);
+    assert_eq!(summary(&out), (3, 3, 0));
+}
+
+#[test]
+fn interval() {
+    let start = Instant::now();
+    let Some(out) = ring_or_skip(&["-c", "3", "-i", "1", "127.0.0.1"]) else {
+        return;
+    };
+    // One interval after each request, the last one included
+    let took = start.elapsed();
+    assert!(took >= Duration::from_secs(3), "{took:?}");
+    assert!(took < Duration::from_secs(6), "{took:?}");
+    assert_eq!(summary(&out), (3, 3, 0));
+}
+
+#[test]
+fn preload() {
+    let start = Instant::now();
+    let Some(out) = ring_or_skip(&["-c", "5", "-l", "5", "-i", "1", "127.0.0.1"]) else {
+        return;
+    };
+    // The burst goes out at once, only the last request waits for its interval
+    assert!(start.elapsed() < Duration::from_secs(3));
+    assert_eq!(replies(&out).len(), 5);
+    assert_eq!(summary(&out), (5, 5, 0));
+}
+
+#[test]
+fn quiet() {
+    let Some(out) = ring_or_skip(&["-q", "-c", "2", "-i", "0", "127.0.0.1"]) else {
+        return;
+    };
+    assert!(out.status.success());
+    assert!(replies(&out).is_empty(), "{}", stdout(&out));
+    assert_eq!(summary(&out), (2, 2, 0));
+}
+
+#[test]
+fn timestamps() {
+    let Some(out) = ring_or_skip(&["-D", "-c", "1", "-i", "0", "127.0.0.1"]) else {
+        return;
+    };
+    let text = stdout(&out);
+    let line = text.lines().find(|l| l.contains("returned")).unwrap();
+    assert!(line.starts_with('['), "{line}");
+}
+
+#[test]
+fn timeout() {
+    // Nothing should answer in TEST-NET-3 (rfc 5737). Hosts without a route to it, or whose
+    // network uses it after all, can't test this.
+    let start = Instant::now();
+    let Some(out) = ring_or_skip(&["-c", "1", "-d", "1", "-n", "203.0.113.1"]) else {
+        return;
+    };
+    if !out.status.success() || !replies(&out).is_empty() {
+        eprintln!("skipping timeout: 203.0.113.1 can't be used here");
+        return;
+    }
+    assert!(stdout(&out).contains("Packet Timed Out. ICMP Sequence Packet: 1"));
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(summary(&out), (0, 1, 100));
+}
+
+// How many lines report `port` as `state` ("open" or "refused")
+fn handshakes(out: &Output, port: u16, state: &str) -> usize {
+    let line = format!("Port {port} {state}. TCP Sequence Packet: ");
+    stdout(out).lines().filter(|l| l.contains(&line)).count()
+}
+
+#[test]
+fn tcp_open() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let out = ring(&[
+        "--tcp",
+        &port.to_string(),
+        "-c",
+        "3",
+        "-i",
+        "0",
+        "127.0.0.1",
+    ]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(handshakes(&out, port, "open"), 3, "{}", stdout(&out));
+    assert_eq!(summary(&out), (3, 3, 0));
+}
+
+#[test]
+fn tcp_refused() {
+    // A port nobody listens on anymore
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let out = ring(&[
+        "--tcp",
+        &port.to_string(),
+        "-c",
+        "2",
+        "-i",
+        "0",
+        "127.0.0.1",
+    ]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    // The host answered, so a refused port isn't loss
+    assert_eq!(handshakes(&out, port, "refused"), 2, "{}", stdout(&out));
+    assert_eq!(summary(&out), (2, 2, 0));
+}
+
+#[test]
+fn help_exits_zero() {
+    let out = ring(&["--help"]);
+    assert!(out.status.success());
+    assert!(stdout(&out).contains("Options:"));
+}
+
+#[test]
+fn bad_arguments_exit_one() {
+    for args in [
+        &["--no-such-option", "127.0.0.1"][..],
+        &["-l", "0", "127.0.0.1"],
+        &["--timestamp=never", "127.0.0.1"],
+        &["--stats-every", "0", "127.0.0.1"],
+        &["--tcp", "0", "127.0.0.1"],
+        &["--tcp", "80", "-r", "127.0.0.1"],
+        &[],
+    ] {
+        let out = ring(args);
+        assert_eq!(out.status.code(), Some(1), "ring {}", args.join(" "));
+    }
+}
+
+#[test]
+fn unresolvable_exits_one() {
+    let Some(out) = ring_or_skip(&["-n", "-c", "1", "not-an-address"]) else {
+        return;
+    };
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("not-an-address"), "{}", stderr(&out));
+}